@@ -4,6 +4,9 @@
 sudo apt-get update
 sudo apt-get install -y libclang-dev libgtk-3-dev libxcb-render0-dev libxcb-shape0-dev libxcb-xfixes0-dev libspeechd-dev libxkbcommon-dev libssl-dev libgtk-3-dev
 
+# Software Vulkan implementation (lavapipe), used to run the headless golden-image tests
+sudo apt-get install -y mesa-vulkan-drivers
+
 # Install assimp from source
 path_apt_sourcelist=/etc/apt/sources.list
 path_assimp_repo=/tmp/assimp
@@ -0,0 +1,342 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use cgmath::{InnerSpace, Point3, Vector3};
+use serde::{Deserialize, Serialize};
+
+use super::camera::{get_controller, Camera, CameraController};
+
+/// Bookmarks are stored next to the working directory, so they can be shared with the assets.
+pub const BOOKMARKS_PATH: &str = "bookmarks.ron";
+
+/// Time between the keyframes of a recorded path, in seconds.
+const RECORDING_INTERVAL: f32 = 0.25;
+
+/// Where the camera is and where it looks.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct CameraPose {
+    pub position: [f32; 3],
+    pub forward: [f32; 3],
+    pub up: [f32; 3],
+}
+
+impl CameraPose {
+    pub fn from_camera(camera: &Camera) -> CameraPose {
+        CameraPose {
+            position: camera.position.into(),
+            forward: camera.forward.into(),
+            up: camera.up.into(),
+        }
+    }
+
+    pub fn apply(&self, camera: &mut Camera) {
+        camera.position = Point3::from(self.position);
+        camera.forward = Vector3::from(self.forward).normalize();
+        camera.up = Vector3::from(self.up).normalize();
+        camera.right = camera.forward.cross(camera.up);
+        camera.velocity = Vector3::new(0.0, 0.0, 0.0);
+    }
+}
+
+/// Restarts `controller` from the current pose of `camera`, so it doesn't move the camera back
+/// to where the controller left it.
+fn restart_controller(camera: &Camera, controller: &mut Box<dyn CameraController>) {
+    *controller = get_controller(controller.get_type(), camera);
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Bookmark {
+    pub name: String,
+    pub pose: CameraPose,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct Keyframe {
+    /// Seconds since the start of the path.
+    pub time: f32,
+    pub pose: CameraPose,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CameraPath {
+    pub name: String,
+    pub keyframes: Vec<Keyframe>,
+}
+
+impl CameraPath {
+    pub fn duration(&self) -> f32 {
+        self.keyframes.last().map_or(0.0, |keyframe| keyframe.time)
+    }
+
+    /// Interpolates the keyframes with a Catmull-Rom spline, which passes through all of them.
+    pub fn get_pose(&self, time: f32) -> Option<CameraPose> {
+        let keyframes = &self.keyframes;
+        let last = keyframes.len().checked_sub(1)?;
+        let i = keyframes
+            .iter()
+            .rposition(|keyframe| keyframe.time <= time)
+            .unwrap_or(0)
+            .min(last.saturating_sub(1));
+
+        let [k0, k1, k2, k3] =
+            [i.saturating_sub(1), i, (i + 1).min(last), (i + 2).min(last)].map(|k| keyframes[k]);
+        let span = k2.time - k1.time;
+        let t = if span > 0.0 {
+            ((time - k1.time) / span).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+
+        let interpolate = |get: fn(&CameraPose) -> [f32; 3]| -> [f32; 3] {
+            let [p0, p1, p2, p3] = [k0, k1, k2, k3].map(|k| Vector3::from(get(&k.pose)));
+            catmull_rom(p0, p1, p2, p3, t).into()
+        };
+
+        Some(CameraPose {
+            position: interpolate(|pose| pose.position),
+            forward: interpolate(|pose| pose.forward),
+            up: interpolate(|pose| pose.up),
+        })
+    }
+}
+
+/// Uniform Catmull-Rom spline between `p1` and `p2`.
+fn catmull_rom(
+    p0: Vector3<f32>,
+    p1: Vector3<f32>,
+    p2: Vector3<f32>,
+    p3: Vector3<f32>,
+    t: f32,
+) -> Vector3<f32> {
+    let (t2, t3) = (t * t, t * t * t);
+
+    (p1 * 2.0
+        + (p2 - p0) * t
+        + (p0 * 2.0 - p1 * 5.0 + p2 * 4.0 - p3) * t2
+        + (p1 * 3.0 - p0 - p2 * 3.0 + p3) * t3)
+        * 0.5
+}
+
+/// The contents of the bookmarks file.
+#[derive(Serialize, Deserialize, Default)]
+pub struct BookmarkFile {
+    pub bookmarks: Vec<Bookmark>,
+    pub paths: Vec<CameraPath>,
+}
+
+pub struct Recording {
+    pub keyframes: Vec<Keyframe>,
+    pub time: f32,
+}
+
+pub struct Playback {
+    pub path: usize,
+    pub time: f32,
+}
+
+/// Named camera poses and paths, which are saved to a RON file whenever they change.
+pub struct CameraBookmarks {
+    pub file: BookmarkFile,
+    pub path: PathBuf,
+    pub recording: Option<Recording>,
+    pub playback: Option<Playback>,
+}
+
+impl CameraBookmarks {
+    /// Starts without bookmarks if the file doesn't exist or can't be read.
+    pub fn load(path: &Path) -> CameraBookmarks {
+        let file = match fs::read_to_string(path) {
+            Ok(text) => ron::from_str(&text).unwrap_or_else(|error| {
+                eprintln!("Could not parse {}: {}", path.display(), error);
+                BookmarkFile::default()
+            }),
+            Err(_) => BookmarkFile::default(),
+        };
+
+        CameraBookmarks {
+            file,
+            path: path.to_owned(),
+            recording: None,
+            playback: None,
+        }
+    }
+
+    pub fn save(&self) {
+        let text =
+            ron::ser::to_string_pretty(&self.file, ron::ser::PrettyConfig::default()).unwrap();
+        if let Err(error) = fs::write(&self.path, text) {
+            eprintln!("Could not save {}: {}", self.path.display(), error);
+        }
+    }
+
+    pub fn add_bookmark(&mut self, name: String, camera: &Camera) {
+        self.file.bookmarks.push(Bookmark {
+            name,
+            pose: CameraPose::from_camera(camera),
+        });
+        self.save();
+    }
+
+    /// Replaces the bookmark at `index`, or adds one if there are fewer bookmarks.
+    pub fn set_bookmark(&mut self, index: usize, camera: &Camera) {
+        let pose = CameraPose::from_camera(camera);
+        match self.file.bookmarks.get_mut(index) {
+            Some(bookmark) => bookmark.pose = pose,
+            None => self.file.bookmarks.push(Bookmark {
+                name: format!("Bookmark {}", self.file.bookmarks.len() + 1),
+                pose,
+            }),
+        }
+        self.save();
+    }
+
+    pub fn go_to(
+        &mut self,
+        index: usize,
+        camera: &mut Camera,
+        controller: &mut Box<dyn CameraController>,
+    ) {
+        if let Some(bookmark) = self.file.bookmarks.get(index) {
+            self.playback = None;
+            bookmark.pose.apply(camera);
+            restart_controller(camera, controller);
+        }
+    }
+
+    pub fn start_recording(&mut self) {
+        self.playback = None;
+        self.recording = Some(Recording {
+            keyframes: vec![],
+            time: 0.0,
+        });
+    }
+
+    /// Stores the recorded path, including the current pose as its last keyframe.
+    pub fn stop_recording(&mut self, name: String, camera: &Camera) {
+        if let Some(mut recording) = self.recording.take() {
+            recording.keyframes.push(Keyframe {
+                time: recording.time,
+                pose: CameraPose::from_camera(camera),
+            });
+            self.file.paths.push(CameraPath {
+                name,
+                keyframes: recording.keyframes,
+            });
+            self.save();
+        }
+    }
+
+    pub fn play(&mut self, path: usize) {
+        self.recording = None;
+        self.playback = Some(Playback { path, time: 0.0 });
+    }
+
+    /// Samples the camera while recording, or moves it along the path that is playing. Returns
+    /// whether a path is playing, in which case the controller should not move the camera.
+    pub fn update(
+        &mut self,
+        camera: &mut Camera,
+        controller: &mut Box<dyn CameraController>,
+        delta_time: f32,
+    ) -> bool {
+        if let Some(recording) = &mut self.recording {
+            let next_sample = recording.keyframes.len() as f32 * RECORDING_INTERVAL;
+            if recording.time >= next_sample {
+                recording.keyframes.push(Keyframe {
+                    time: recording.time,
+                    pose: CameraPose::from_camera(camera),
+                });
+            }
+            recording.time += delta_time;
+        }
+
+        let playback = match &mut self.playback {
+            Some(playback) => playback,
+            None => return false,
+        };
+        let path = match self.file.paths.get(playback.path) {
+            Some(path) => path,
+            None => {
+                self.playback = None;
+                return false;
+            }
+        };
+
+        if let Some(pose) = path.get_pose(playback.time.min(path.duration())) {
+            pose.apply(camera);
+        }
+        playback.time += delta_time;
+
+        if playback.time > path.duration() {
+            self.playback = None;
+            restart_controller(camera, controller);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyframe(time: f32, x: f32) -> Keyframe {
+        Keyframe {
+            time,
+            pose: CameraPose {
+                position: [x, 2.0 * x, 0.0],
+                forward: [0.0, 0.0, 1.0],
+                up: [0.0, 1.0, 0.0],
+            },
+        }
+    }
+
+    fn path(keyframes: Vec<Keyframe>) -> CameraPath {
+        CameraPath {
+            name: "path".to_owned(),
+            keyframes,
+        }
+    }
+
+    fn assert_position(pose: Option<CameraPose>, expected: [f32; 3]) {
+        let position = Vector3::from(pose.unwrap().position);
+        assert!(
+            (position - Vector3::from(expected)).magnitude() < 1.0e-4,
+            "{:?} is not {:?}",
+            position,
+            expected
+        );
+    }
+
+    #[test]
+    fn empty_path_has_no_pose() {
+        assert!(path(vec![]).get_pose(0.0).is_none());
+    }
+
+    #[test]
+    fn single_keyframe_is_used_at_any_time() {
+        let path = path(vec![keyframe(1.0, 3.0)]);
+        for time in [0.0, 1.0, 5.0] {
+            assert_position(path.get_pose(time), [3.0, 6.0, 0.0]);
+        }
+    }
+
+    #[test]
+    fn path_passes_through_every_keyframe() {
+        let keyframes = vec![
+            keyframe(0.0, 0.0),
+            keyframe(0.5, 4.0),
+            keyframe(2.0, -1.0),
+            keyframe(3.0, 7.0),
+        ];
+        let path = path(keyframes.clone());
+
+        for keyframe in keyframes {
+            assert_position(path.get_pose(keyframe.time), keyframe.pose.position);
+        }
+        // Times outside of the path are clamped to its ends.
+        assert_position(path.get_pose(-1.0), [0.0, 0.0, 0.0]);
+        assert_position(path.get_pose(10.0), [7.0, 14.0, 0.0]);
+    }
+}
@@ -0,0 +1,264 @@
+use cgmath::{EuclideanSpace, InnerSpace, Matrix, Matrix4, MetricSpace, Point3, Vector3, Vector4};
+
+/// Axis-aligned bounding box.
+#[derive(Clone, Copy, Debug)]
+pub struct Aabb {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>,
+}
+
+impl Aabb {
+    /// Returns an empty box at the origin if there are no points.
+    pub fn from_points(points: impl IntoIterator<Item = Point3<f32>>) -> Aabb {
+        let mut points = points.into_iter();
+        let first = match points.next() {
+            Some(first) => first,
+            None => {
+                return Aabb {
+                    min: Point3::origin(),
+                    max: Point3::origin(),
+                }
+            }
+        };
+
+        points.fold(
+            Aabb {
+                min: first,
+                max: first,
+            },
+            |aabb, p| Aabb {
+                min: Point3::new(
+                    aabb.min.x.min(p.x),
+                    aabb.min.y.min(p.y),
+                    aabb.min.z.min(p.z),
+                ),
+                max: Point3::new(
+                    aabb.max.x.max(p.x),
+                    aabb.max.y.max(p.y),
+                    aabb.max.z.max(p.z),
+                ),
+            },
+        )
+    }
+
+    pub fn center(&self) -> Point3<f32> {
+        self.min.midpoint(self.max)
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb::from_points([self.min, self.max, other.min, other.max])
+    }
+
+    /// Returns how far the box extends along `direction`, as the largest dot product of
+    /// `direction` with any point of the box.
+    pub fn extent_along(&self, direction: Vector3<f32>) -> f32 {
+        (0..3)
+            .map(|axis| (self.min[axis] * direction[axis]).max(self.max[axis] * direction[axis]))
+            .sum()
+    }
+
+    /// Returns the box around the transformed box, which may be larger than the transformed
+    /// points themselves.
+    pub fn transform(&self, matrix: &Matrix4<f32>) -> Aabb {
+        // Arvo's method: every row of the rotation and scale adds the smaller and larger of the
+        // products with the minimum and maximum to the translation.
+        let translation = matrix.w.truncate();
+        let mut min = translation;
+        let mut max = translation;
+
+        for column in 0..3 {
+            for row in 0..3 {
+                let a = matrix[column][row] * self.min[column];
+                let b = matrix[column][row] * self.max[column];
+                min[row] += a.min(b);
+                max[row] += a.max(b);
+            }
+        }
+
+        Aabb {
+            min: Point3::from_vec(min),
+            max: Point3::from_vec(max),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BoundingSphere {
+    pub center: Point3<f32>,
+    pub radius: f32,
+}
+
+impl BoundingSphere {
+    /// Returns the sphere around `points` centered on their bounding box, which is not the
+    /// smallest one but close enough for culling.
+    pub fn from_points(points: &[Point3<f32>], aabb: &Aabb) -> BoundingSphere {
+        let center = aabb.center();
+        let radius = points
+            .iter()
+            .map(|p| p.distance2(center))
+            .fold(0.0, f32::max)
+            .sqrt();
+
+        BoundingSphere { center, radius }
+    }
+
+    pub fn transform(&self, matrix: &Matrix4<f32>) -> BoundingSphere {
+        // Non-uniform scales stretch the sphere along the axis with the largest scale.
+        let scale = [matrix.x, matrix.y, matrix.z]
+            .iter()
+            .map(|axis| axis.truncate().magnitude())
+            .fold(0.0, f32::max);
+
+        BoundingSphere {
+            center: Point3::from_homogeneous(matrix * self.center.to_homogeneous()),
+            radius: self.radius * scale,
+        }
+    }
+}
+
+/// The planes of a view frustum, with their normals pointing inwards.
+pub struct Frustum {
+    planes: [Vector4<f32>; 6],
+}
+
+impl Frustum {
+    /// Extracts the planes from the rows of a projection times view matrix, as described by
+    /// Gribb and Hartmann, for the [0, 1] depth range of Vulkan.
+    pub fn from_matrix(view_proj: &Matrix4<f32>) -> Frustum {
+        let [x, y, z, w] = [0, 1, 2, 3].map(|i| view_proj.row(i));
+        let planes = [w + x, w - x, w + y, w - y, z, w - z].map(|plane| {
+            let length = plane.truncate().magnitude();
+            if length == 0.0 {
+                // The far plane of an infinite projection, which every point is in front of.
+                Vector4::new(0.0, 0.0, 0.0, 1.0)
+            } else {
+                // Normalized, so the plane equation gives the distance to the plane.
+                plane / length
+            }
+        });
+
+        Frustum { planes }
+    }
+
+    fn distance(plane: &Vector4<f32>, point: Vector3<f32>) -> f32 {
+        plane.truncate().dot(point) + plane.w
+    }
+
+    pub fn intersects_sphere(&self, sphere: &BoundingSphere) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| Frustum::distance(plane, sphere.center.to_vec()) >= -sphere.radius)
+    }
+
+    /// Tests the corner of the box furthest along the normal of each plane, so boxes that are
+    /// close to a corner of the frustum may still count as intersecting it.
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            let corner = Vector3::from([0, 1, 2].map(|i| {
+                if plane[i] >= 0.0 {
+                    aabb.max[i]
+                } else {
+                    aabb.min[i]
+                }
+            }));
+            Frustum::distance(plane, corner) >= 0.0
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Deg, Rad};
+
+    use super::*;
+    use crate::atlas_core::camera::perspective;
+
+    fn cube(center: Point3<f32>, half_size: f32) -> Aabb {
+        let half_size = Vector3::new(half_size, half_size, half_size);
+        Aabb {
+            min: center - half_size,
+            max: center + half_size,
+        }
+    }
+
+    /// Looks down -Z from the origin with a 90 degree field of view, so the side planes are at
+    /// 45 degrees.
+    fn frustum(reverse_z: bool) -> Frustum {
+        let proj = perspective(Rad::from(Deg(90.0)), 1.0, 1.0, 100.0, reverse_z);
+        Frustum::from_matrix(&proj)
+    }
+
+    #[test]
+    fn boxes_inside_and_outside_of_the_frustum() {
+        let frustum = frustum(false);
+
+        assert!(frustum.intersects_aabb(&cube(Point3::new(0.0, 0.0, -10.0), 1.0)));
+        // Behind the camera, beyond the far plane, and beside the frustum.
+        assert!(!frustum.intersects_aabb(&cube(Point3::new(0.0, 0.0, 10.0), 1.0)));
+        assert!(!frustum.intersects_aabb(&cube(Point3::new(0.0, 0.0, -200.0), 1.0)));
+        assert!(!frustum.intersects_aabb(&cube(Point3::new(20.0, 0.0, -10.0), 1.0)));
+    }
+
+    #[test]
+    fn boxes_straddling_a_plane_intersect_the_frustum() {
+        let frustum = frustum(false);
+
+        // Across the right plane, the near plane and the far plane.
+        assert!(frustum.intersects_aabb(&cube(Point3::new(10.0, 0.0, -10.0), 1.0)));
+        assert!(frustum.intersects_aabb(&cube(Point3::new(0.0, 0.0, 0.0), 2.0)));
+        assert!(frustum.intersects_aabb(&cube(Point3::new(0.0, 0.0, -100.0), 2.0)));
+    }
+
+    #[test]
+    fn spheres_inside_and_outside_of_the_frustum() {
+        let frustum = frustum(false);
+        let sphere = |x, z, radius| BoundingSphere {
+            center: Point3::new(x, 0.0, z),
+            radius,
+        };
+
+        assert!(frustum.intersects_sphere(&sphere(0.0, -10.0, 1.0)));
+        assert!(!frustum.intersects_sphere(&sphere(0.0, 10.0, 1.0)));
+        // The center is 10 / sqrt(2) from the right plane.
+        assert!(!frustum.intersects_sphere(&sphere(20.0, -10.0, 7.0)));
+        assert!(frustum.intersects_sphere(&sphere(20.0, -10.0, 7.2)));
+    }
+
+    #[test]
+    fn infinite_far_plane_keeps_distant_boxes() {
+        let frustum = frustum(true);
+
+        assert!(frustum.intersects_aabb(&cube(Point3::new(0.0, 0.0, -1.0e6), 1.0)));
+        assert!(frustum.intersects_aabb(&cube(Point3::new(0.0, 0.0, -10.0), 1.0)));
+        assert!(!frustum.intersects_aabb(&cube(Point3::new(0.0, 0.0, 10.0), 1.0)));
+        assert!(!frustum.intersects_aabb(&cube(Point3::new(1.0e6, 0.0, -10.0), 1.0)));
+    }
+
+    #[test]
+    fn rotated_box_grows_to_contain_its_corners() {
+        let aabb = cube(Point3::origin(), 1.0);
+        let matrix = Matrix4::from_translation(Vector3::new(5.0, 0.0, 0.0))
+            * Matrix4::from_angle_z(Deg(45.0));
+        let rotated = aabb.transform(&matrix);
+
+        let extent = 2.0f32.sqrt();
+        let expected_min = Point3::new(5.0 - extent, -extent, -1.0);
+        let expected_max = Point3::new(5.0 + extent, extent, 1.0);
+        assert!((rotated.min - expected_min).magnitude() < 1.0e-5);
+        assert!((rotated.max - expected_max).magnitude() < 1.0e-5);
+    }
+
+    #[test]
+    fn extent_along_picks_the_farthest_corner() {
+        let aabb = Aabb {
+            min: Point3::new(-1.0, 2.0, 0.0),
+            max: Point3::new(3.0, 4.0, 1.0),
+        };
+
+        assert_eq!(aabb.extent_along(Vector3::unit_x()), 3.0);
+        assert_eq!(aabb.extent_along(-Vector3::unit_x()), 1.0);
+        assert_eq!(aabb.extent_along(-Vector3::unit_y()), -2.0);
+        // The corner at (3, 2, 1).
+        assert_eq!(aabb.extent_along(Vector3::new(1.0, -1.0, 1.0)), 2.0);
+    }
+}
@@ -1,40 +1,172 @@
 #![allow(dead_code)]
 
-use crate::WinitInputHelper;
-use cgmath::{InnerSpace, Matrix3, Matrix4, Point3, Rad, Vector3};
+use cgmath::{Deg, InnerSpace, Matrix3, Matrix4, Point3, Rad, Vector3, Zero};
 use winit::event::VirtualKeyCode;
+use winit_input_helper::WinitInputHelper;
+
+use crate::atlas_core::bounds::Aabb;
 
 const MOUSE_BUTTON_LEFT: usize = 0;
 const MOUSE_BUTTON_RIGHT: usize = 1;
 const MOUSE_BUTTON_MIDDLE: usize = 2;
 
+#[derive(PartialEq, Clone, Copy)]
+pub enum Projection {
+    Perspective,
+    Orthographic,
+}
+
+impl Projection {
+    pub fn get_text(&self) -> &str {
+        match self {
+            Projection::Perspective => "Perspective",
+            Projection::Orthographic => "Orthographic",
+        }
+    }
+}
+
+/// How the camera moves in response to the keyboard and mouse.
+#[derive(Clone, Copy)]
+pub struct MovementSettings {
+    /// Top speed in units per second.
+    pub speed: f32,
+    /// Multiplies the top speed while shift is held.
+    pub boost: f32,
+    /// Rate at which the velocity approaches the top speed while a key is held, per second.
+    pub acceleration: f32,
+    /// Rate at which the velocity decays once the keys are released, per second.
+    pub damping: f32,
+    /// Radians per pixel of mouse movement.
+    pub rotate_speed: f32,
+}
+
+impl Default for MovementSettings {
+    fn default() -> Self {
+        MovementSettings {
+            speed: 200.0,
+            boost: 5.0,
+            acceleration: 10.0,
+            damping: 8.0,
+            rotate_speed: 0.005,
+        }
+    }
+}
+
 pub struct Camera {
     pub position: Point3<f32>,
     pub forward: Vector3<f32>,
     pub right: Vector3<f32>,
     pub up: Vector3<f32>,
 
+    pub projection: Projection,
+    /// Vertical field of view of the perspective projection.
+    pub fov_y: Deg<f32>,
+    /// Height of the view volume of the orthographic projection.
+    pub ortho_height: f32,
+    pub near: f32,
+    /// Not used by the perspective projection with `reverse_z`, which has no far plane.
+    pub far: f32,
+    /// Maps the near plane to depth 1 and the far plane to 0. Together with a floating point
+    /// depth buffer, this keeps the depth precision nearly constant over the distance.
+    pub reverse_z: bool,
+
+    pub movement: MovementSettings,
+    /// World space velocity in units per second.
+    pub velocity: Vector3<f32>,
+
     pub aspect_ratio: f32,
     pub proj: Matrix4<f32>,
     pub view: Matrix4<f32>,
-    pub world: Matrix4<f32>,
-    pub world_view: Matrix4<f32>,
 
     pub mouse_rotation_start_coord: (f32, f32),
 }
 
 impl Camera {
     pub fn update(&mut self) {
-        self.proj = cgmath::perspective(
-            Rad(std::f32::consts::FRAC_PI_2),
-            self.aspect_ratio,
-            5.0,
-            10000.0,
-        );
+        self.proj = match self.projection {
+            Projection::Perspective => perspective(
+                self.fov_y.into(),
+                self.aspect_ratio,
+                self.near,
+                self.far,
+                self.reverse_z,
+            ),
+            Projection::Orthographic => orthographic(
+                self.ortho_height * self.aspect_ratio,
+                self.ortho_height,
+                self.near,
+                self.far,
+                self.reverse_z,
+            ),
+        };
 
         self.view = Matrix4::look_to_rh(self.position, self.forward, self.up);
-        self.world_view = self.view * self.world;
     }
+
+    /// Distance to the far plane, which is infinite for the perspective projection with
+    /// `reverse_z`.
+    pub fn far_distance(&self) -> f32 {
+        if self.reverse_z && self.projection == Projection::Perspective {
+            f32::INFINITY
+        } else {
+            self.far
+        }
+    }
+
+    /// Returns the corners of the cross section of the view volume at `depth` in front of the
+    /// camera.
+    pub fn get_frustum_slice(&self, depth: f32) -> [Point3<f32>; 4] {
+        let half_height = match self.projection {
+            Projection::Perspective => depth * (Rad::from(self.fov_y) / 2.0).0.tan(),
+            Projection::Orthographic => self.ortho_height / 2.0,
+        };
+        let half_width = half_height * self.aspect_ratio;
+        let center = self.position + self.forward * depth;
+
+        [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)]
+            .map(|(x, y)| center + self.right * x * half_width + self.up * y * half_height)
+    }
+}
+
+/// A right-handed perspective projection to the [0, 1] depth range of Vulkan. With `reverse_z`,
+/// the depth range is reversed and the far plane is moved to infinity.
+pub fn perspective(
+    fov_y: Rad<f32>,
+    aspect: f32,
+    near: f32,
+    far: f32,
+    reverse_z: bool,
+) -> Matrix4<f32> {
+    let f = 1.0 / (fov_y.0 / 2.0).tan();
+    let (a, b) = if reverse_z {
+        (0.0, near)
+    } else {
+        (far / (near - far), near * far / (near - far))
+    };
+
+    Matrix4::from([
+        [f / aspect, 0.0, 0.0, 0.0],
+        [0.0, f, 0.0, 0.0],
+        [0.0, 0.0, a, -1.0],
+        [0.0, 0.0, b, 0.0],
+    ])
+}
+
+/// A right-handed orthographic projection to the [0, 1] depth range of Vulkan, or [1, 0] with
+/// `reverse_z`.
+pub fn orthographic(width: f32, height: f32, near: f32, far: f32, reverse_z: bool) -> Matrix4<f32> {
+    let (a, b) = if reverse_z {
+        (1.0 / (far - near), far / (far - near))
+    } else {
+        (1.0 / (near - far), near / (near - far))
+    };
+
+    Matrix4::from([
+        [2.0 / width, 0.0, 0.0, 0.0],
+        [0.0, 2.0 / height, 0.0, 0.0],
+        [0.0, 0.0, a, 0.0],
+        [0.0, 0.0, b, 1.0],
+    ])
 }
 
 pub fn construct_camera() -> Camera {
@@ -48,65 +180,318 @@ pub fn construct_camera() -> Camera {
         forward,
         up,
         right: forward.cross(up),
+        projection: Projection::Perspective,
+        fov_y: Deg(90.0),
+        ortho_height: 1000.0,
+        near: 1.0,
+        far: 10000.0,
+        reverse_z: true,
+        movement: MovementSettings::default(),
+        velocity: Vector3::zero(),
         aspect_ratio: 1.0,
         proj: Matrix4::from_scale(1.0),
         view: Matrix4::from_scale(1.0),
-        world: Matrix4::from_scale(1.0),
-        world_view: Matrix4::from_scale(1.0),
         mouse_rotation_start_coord: (0.0, 0.0),
     }
 }
 
-pub trait CameraInputLogic {
-    fn handle_event(&mut self, input: &WinitInputHelper);
+#[derive(PartialEq, Clone, Copy)]
+pub enum ControllerType {
+    Fly,
+    Orbit,
 }
 
-impl CameraInputLogic for Camera {
-    fn handle_event(&mut self, input: &WinitInputHelper) {
-        let mut move_speed = 1.0; // 1 / dt
-        let rotate_speed = 0.005; // rad / (px * dt)
-
-        if input.held_shift() {
-            move_speed *= 5.0;
+impl ControllerType {
+    pub fn get_text(&self) -> &str {
+        match self {
+            ControllerType::Fly => "Fly",
+            ControllerType::Orbit => "Orbit",
         }
+    }
+}
 
-        if input.key_held(VirtualKeyCode::W) {
-            self.position += self.forward * move_speed;
-        }
-        if input.key_held(VirtualKeyCode::S) {
-            self.position -= self.forward * move_speed;
-        }
-        if input.key_held(VirtualKeyCode::A) {
-            self.position -= self.right * move_speed;
+/// The input the UI is using, which the controllers ignore.
+#[derive(Clone, Copy, Default)]
+pub struct UiFocus {
+    /// The pointer is over the UI or dragging a widget, so mouse buttons and scrolling are
+    /// meant for the UI.
+    pub pointer: bool,
+    /// A text field has the keyboard focus, so keys are meant for the UI.
+    pub keyboard: bool,
+}
+
+/// Moves a camera in response to the keyboard and mouse.
+pub trait CameraController {
+    fn get_type(&self) -> ControllerType;
+
+    /// Called once per frame with the time since the previous frame in seconds.
+    fn handle_input(
+        &mut self,
+        camera: &mut Camera,
+        input: &WinitInputHelper,
+        focus: UiFocus,
+        delta_time: f32,
+    );
+
+    /// Moves the camera so that `bounds` fills the view, keeping its direction.
+    fn frame(&mut self, camera: &mut Camera, bounds: &Aabb);
+}
+
+/// Creates a controller of the given type that continues from the current view of `camera`.
+pub fn get_controller(
+    controller_type: ControllerType,
+    camera: &Camera,
+) -> Box<dyn CameraController> {
+    match controller_type {
+        ControllerType::Fly => Box::new(FlyController),
+        ControllerType::Orbit => Box::new(OrbitController::new(camera, ORBIT_DISTANCE)),
+    }
+}
+
+/// Moves the camera back along its view direction until the sphere around `bounds` fits in the
+/// view, and returns the distance to the center of the sphere.
+fn frame_bounds(camera: &mut Camera, bounds: &Aabb) -> f32 {
+    let center = bounds.center();
+    let radius = (bounds.max - bounds.min).magnitude() / 2.0;
+
+    let distance = match camera.projection {
+        Projection::Perspective => {
+            let half_fov_y = Rad::from(camera.fov_y).0 / 2.0;
+            let half_fov_x = (half_fov_y.tan() * camera.aspect_ratio).atan();
+            radius / half_fov_y.min(half_fov_x).sin()
         }
-        if input.key_held(VirtualKeyCode::D) {
-            self.position += self.right * move_speed;
+        Projection::Orthographic => {
+            camera.ortho_height = 2.0 * radius / camera.aspect_ratio.min(1.0);
+            2.0 * radius
         }
-        if input.key_held(VirtualKeyCode::E) {
-            self.position -= self.up * move_speed;
+    };
+    // Keeps the front of the sphere beyond the near plane.
+    let distance = distance.max(radius + camera.near);
+
+    camera.position = center - camera.forward * distance;
+    distance
+}
+
+/// Free-fly controller: WASD to move, E and F to move down and up, shift to move faster and
+/// the right mouse button to look around.
+pub struct FlyController;
+
+impl CameraController for FlyController {
+    fn get_type(&self) -> ControllerType {
+        ControllerType::Fly
+    }
+
+    fn handle_input(
+        &mut self,
+        camera: &mut Camera,
+        input: &WinitInputHelper,
+        focus: UiFocus,
+        delta_time: f32,
+    ) {
+        let settings = camera.movement;
+
+        let mut direction = Vector3::zero();
+        for (key, axis) in [
+            (VirtualKeyCode::W, camera.forward),
+            (VirtualKeyCode::S, -camera.forward),
+            (VirtualKeyCode::A, -camera.right),
+            (VirtualKeyCode::D, camera.right),
+            (VirtualKeyCode::E, -camera.up),
+            (VirtualKeyCode::F, camera.up),
+        ] {
+            // While typing into the UI, the camera only slows down.
+            if !focus.keyboard && input.key_held(key) {
+                direction += axis;
+            }
         }
-        if input.key_held(VirtualKeyCode::F) {
-            self.position += self.up * move_speed;
+
+        // Exponential smoothing towards the target velocity, which gives the same motion at any
+        // frame rate.
+        let (target, rate) = if direction.magnitude2() > 0.0 {
+            let boost = if input.held_shift() {
+                settings.boost
+            } else {
+                1.0
+            };
+            (
+                direction.normalize() * settings.speed * boost,
+                settings.acceleration,
+            )
+        } else {
+            (Vector3::zero(), settings.damping)
+        };
+        let blend = 1.0 - (-rate * delta_time).exp();
+        camera.velocity += (target - camera.velocity) * blend;
+        camera.position += camera.velocity * delta_time;
+
+        if focus.pointer {
+            return;
         }
 
         if input.mouse_pressed(MOUSE_BUTTON_RIGHT) {
-            self.mouse_rotation_start_coord = input.mouse().unwrap_or((0.0, 0.0));
+            camera.mouse_rotation_start_coord = input.mouse().unwrap_or((0.0, 0.0));
         }
 
         if input.mouse_held(MOUSE_BUTTON_RIGHT) {
             let diff = input.mouse_diff();
-            let transform = Matrix3::from_axis_angle(self.up, Rad(-diff.0 * rotate_speed))
-                * Matrix3::from_axis_angle(self.right, Rad(diff.1 * rotate_speed));
+            // The mouse moved the same distance regardless of the frame time, so this isn't
+            // scaled by it.
+            let rotate_speed = settings.rotate_speed;
+            let transform = Matrix3::from_axis_angle(camera.up, Rad(-diff.0 * rotate_speed))
+                * Matrix3::from_axis_angle(camera.right, Rad(diff.1 * rotate_speed));
 
-            self.forward = (transform * self.forward).normalize();
+            camera.forward = (transform * camera.forward).normalize();
 
             if input.held_control() {
-                self.up = (transform * self.up).normalize();
+                camera.up = (transform * camera.up).normalize();
             } else {
-                self.up = Vector3::unit_y();
+                camera.up = Vector3::unit_y();
+            }
+
+            camera.right = camera.forward.cross(camera.up);
+        }
+    }
+
+    fn frame(&mut self, camera: &mut Camera, bounds: &Aabb) {
+        camera.velocity = Vector3::zero();
+        frame_bounds(camera, bounds);
+    }
+}
+
+/// Distance to the focus point when switching to the orbit controller.
+const ORBIT_DISTANCE: f32 = 500.0;
+
+/// Orbits around a focus point: the right mouse button rotates, the middle mouse button pans
+/// and the scroll wheel zooms.
+pub struct OrbitController {
+    pub focus: Point3<f32>,
+    pub distance: f32,
+    /// Rotation around the world up axis, zero when looking along +Z.
+    pub yaw: Rad<f32>,
+    pub pitch: Rad<f32>,
+    /// Fraction of the distance to zoom in per scrolled line.
+    pub zoom_speed: f32,
+    /// Fraction of the distance to pan per pixel of mouse movement.
+    pub pan_speed: f32,
+}
+
+impl OrbitController {
+    /// Orbits around the point `distance` in front of `camera`.
+    pub fn new(camera: &Camera, distance: f32) -> OrbitController {
+        let forward = camera.forward.normalize();
+
+        OrbitController {
+            focus: camera.position + forward * distance,
+            distance,
+            yaw: Rad(forward.x.atan2(forward.z)),
+            pitch: Rad(forward.y.clamp(-1.0, 1.0).asin()),
+            zoom_speed: 0.1,
+            pan_speed: 0.002,
+        }
+    }
+
+    fn apply(&self, camera: &mut Camera) {
+        let (yaw, pitch) = (self.yaw.0, self.pitch.0);
+        camera.forward = Vector3::new(
+            pitch.cos() * yaw.sin(),
+            pitch.sin(),
+            pitch.cos() * yaw.cos(),
+        );
+        camera.up = Vector3::unit_y();
+        camera.right = camera.forward.cross(camera.up);
+        camera.position = self.focus - camera.forward * self.distance;
+    }
+}
+
+impl CameraController for OrbitController {
+    fn get_type(&self) -> ControllerType {
+        ControllerType::Orbit
+    }
+
+    fn handle_input(
+        &mut self,
+        camera: &mut Camera,
+        input: &WinitInputHelper,
+        focus: UiFocus,
+        _delta_time: f32,
+    ) {
+        if !focus.pointer {
+            let diff = input.mouse_diff();
+
+            if input.mouse_held(MOUSE_BUTTON_RIGHT) {
+                // Same directions as looking around with the fly controller.
+                let rotate_speed = camera.movement.rotate_speed;
+                let max_pitch = std::f32::consts::FRAC_PI_2 - 0.01;
+                self.yaw -= Rad(diff.0 * rotate_speed);
+                self.pitch =
+                    Rad((self.pitch.0 + diff.1 * rotate_speed).clamp(-max_pitch, max_pitch));
+            }
+
+            if input.mouse_held(MOUSE_BUTTON_MIDDLE) {
+                let pan = self.distance * self.pan_speed;
+                self.focus += camera.up * diff.1 * pan - camera.right * diff.0 * pan;
             }
 
-            self.right = self.forward.cross(self.up);
+            self.distance *= (-input.scroll_diff() * self.zoom_speed).exp();
+            self.distance = self.distance.max(camera.near);
         }
+
+        camera.velocity = Vector3::zero();
+        self.apply(camera);
+    }
+
+    fn frame(&mut self, camera: &mut Camera, bounds: &Aabb) {
+        self.apply(camera);
+        self.distance = frame_bounds(camera, bounds);
+        self.focus = bounds.center();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::Vector4;
+
+    use super::*;
+
+    /// Depth of the point `distance` in front of the camera after the perspective divide.
+    fn depth(proj: Matrix4<f32>, distance: f32) -> f32 {
+        let clip = proj * Vector4::new(0.0, 0.0, -distance, 1.0);
+        clip.z / clip.w
+    }
+
+    fn assert_near(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1.0e-5,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn perspective_maps_near_and_far_to_the_depth_range() {
+        let proj = perspective(Rad::from(Deg(60.0)), 1.5, 0.5, 200.0, false);
+        assert_near(depth(proj, 0.5), 0.0);
+        assert_near(depth(proj, 200.0), 1.0);
+    }
+
+    #[test]
+    fn reverse_z_perspective_maps_near_to_one_and_infinity_to_zero() {
+        let proj = perspective(Rad::from(Deg(60.0)), 1.5, 0.5, 200.0, true);
+        assert_near(depth(proj, 0.5), 1.0);
+        // The far plane is at infinity, so depth only approaches zero.
+        assert_near(depth(proj, 200.0), 0.5 / 200.0);
+        assert_near(depth(proj, 1.0e9), 0.0);
+    }
+
+    #[test]
+    fn orthographic_maps_near_and_far_to_the_depth_range() {
+        let proj = orthographic(100.0, 50.0, 1.0, 300.0, false);
+        assert_near(depth(proj, 1.0), 0.0);
+        assert_near(depth(proj, 300.0), 1.0);
+
+        let proj = orthographic(100.0, 50.0, 1.0, 300.0, true);
+        assert_near(depth(proj, 1.0), 1.0);
+        assert_near(depth(proj, 300.0), 0.0);
     }
 }
@@ -0,0 +1,94 @@
+use std::{
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use vulkano::{
+    buffer::{BufferUsage, CpuAccessibleBuffer},
+    command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer},
+    device::Device,
+    format::Format,
+    image::ImageAccess,
+};
+
+/// A copy of a rendered image that is readable by the CPU once the command buffer that recorded
+/// it has finished executing.
+pub struct Capture {
+    pub buffer: Arc<CpuAccessibleBuffer<[u8]>>,
+    pub dimensions: [u32; 2],
+    pub format: Format,
+}
+
+impl Capture {
+    /// Returns the captured pixels as tightly packed 8-bit RGBA.
+    pub fn to_rgba(&self) -> Vec<u8> {
+        let content = self.buffer.read().unwrap();
+
+        match self.format {
+            Format::R8G8B8A8_SRGB | Format::R8G8B8A8_UNORM => content.to_vec(),
+            Format::B8G8R8A8_SRGB | Format::B8G8R8A8_UNORM => content
+                .chunks(4)
+                .flat_map(|x| [x[2], x[1], x[0], x[3]])
+                .collect(),
+            format => panic!("Capturing images of format {:?} is not supported", format),
+        }
+    }
+
+    pub fn save_png(&self, path: &Path) -> Result<(), png::EncodingError> {
+        write_png(path, self.dimensions, &self.to_rgba())
+    }
+}
+
+/// Records a copy of `image` into a CPU accessible buffer.
+pub fn record_capture(
+    device: &Arc<Device>,
+    builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+    image: Arc<dyn ImageAccess>,
+) -> Capture {
+    let dimensions = image.dimensions().width_height();
+    let format = image.format();
+    let size = dimensions[0] * dimensions[1] * 4;
+
+    let buffer = CpuAccessibleBuffer::from_iter(
+        device.clone(),
+        BufferUsage::transfer_destination(),
+        true,
+        (0..size).map(|_| 0u8),
+    )
+    .unwrap();
+
+    builder.copy_image_to_buffer(image, buffer.clone()).unwrap();
+
+    Capture {
+        buffer,
+        dimensions,
+        format,
+    }
+}
+
+pub fn write_png(path: &Path, dimensions: [u32; 2], rgba: &[u8]) -> Result<(), png::EncodingError> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let file = File::create(path)?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), dimensions[0], dimensions[1]);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(rgba)
+}
+
+/// Generates a unique path in the `screenshots` directory.
+pub fn screenshot_path() -> PathBuf {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+
+    PathBuf::from(format!("screenshots/atlas_{}.png", timestamp))
+}
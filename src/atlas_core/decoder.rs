@@ -0,0 +1,431 @@
+use std::io::Cursor;
+
+use png::{ColorType, Transformations};
+use russimp::texture::Texel;
+use vulkano::format::Format;
+
+use crate::atlas_core::error::AssetError;
+
+pub struct DecodedImage {
+    pub width: u32,
+    pub height: u32,
+    pub data: ImageData,
+}
+
+pub enum ImageData {
+    /// 8-bit RGBA texels, the mip levels are generated when uploading.
+    Rgba(Vec<u8>),
+    /// Texels in a format the GPU can sample directly, with all of their mip levels. Whether the
+    /// `linear` or `srgb` variant is used depends on what the texture is used for.
+    Native {
+        linear: Format,
+        srgb: Format,
+        levels: Vec<Vec<u8>>,
+    },
+}
+
+pub trait TextureDecoder: Send + Sync {
+    /// File extensions and assimp format hints of the images this decoder reads, in lowercase.
+    fn extensions(&self) -> &[&str];
+
+    /// Whether `data` starts with the magic bytes of this decoder's format.
+    fn matches(&self, data: &[u8]) -> bool;
+
+    fn decode(&self, data: &[u8]) -> Result<DecodedImage, AssetError>;
+}
+
+/// Picks the decoder of an image by its magic bytes, or by its extension if no decoder
+/// recognizes the data.
+pub struct DecoderRegistry {
+    decoders: Vec<Box<dyn TextureDecoder>>,
+}
+
+impl DecoderRegistry {
+    /// A registry without any decoders, see `default` for one with all built-in decoders.
+    pub fn new() -> DecoderRegistry {
+        DecoderRegistry { decoders: vec![] }
+    }
+
+    /// Decoders registered later take precedence over earlier ones for the same format.
+    pub fn register(&mut self, decoder: impl TextureDecoder + 'static) {
+        self.decoders.insert(0, Box::new(decoder));
+    }
+
+    /// `extension` is a file extension or an assimp format hint, without the leading dot.
+    pub fn decode(&self, extension: &str, data: &[u8]) -> Result<DecodedImage, AssetError> {
+        let extension = extension.to_lowercase();
+
+        self.decoders
+            .iter()
+            .find(|decoder| decoder.matches(data))
+            .or_else(|| {
+                self.decoders
+                    .iter()
+                    .find(|decoder| decoder.extensions().contains(&extension.as_str()))
+            })
+            .ok_or(AssetError::UnsupportedFormat(extension.clone()))?
+            .decode(data)
+    }
+}
+
+impl Default for DecoderRegistry {
+    fn default() -> Self {
+        let mut registry = DecoderRegistry::new();
+        registry.register(PngDecoder);
+        registry.register(JpegDecoder);
+        registry.register(Ktx2Decoder);
+        registry
+    }
+}
+
+pub struct PngDecoder;
+
+impl TextureDecoder for PngDecoder {
+    fn extensions(&self) -> &[&str] {
+        &["png"]
+    }
+
+    fn matches(&self, data: &[u8]) -> bool {
+        data.starts_with(b"\x89PNG\r\n\x1a\n")
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<DecodedImage, AssetError> {
+        let mut decoder = png::Decoder::new(Cursor::new(data));
+        // Expands palettes, transparency chunks and bit depths below 8 to 8-bit gray or RGB(A),
+        // and reduces 16-bit channels to 8 bits.
+        decoder.set_transformations(Transformations::EXPAND | Transformations::STRIP_16);
+        let mut reader = decoder.read_info()?;
+
+        let mut image_data = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut image_data)?;
+        image_data.truncate(info.buffer_size());
+
+        let (width, height) = (info.width, info.height);
+
+        let rgba: Vec<u8> = match info.color_type {
+            ColorType::Rgba => image_data,
+            ColorType::Rgb => image_data
+                .chunks(3)
+                .flat_map(|x| [x[0], x[1], x[2], 255])
+                .collect(),
+            ColorType::GrayscaleAlpha => image_data
+                .chunks(2)
+                .flat_map(|x| [x[0], x[0], x[0], x[1]])
+                .collect(),
+            ColorType::Grayscale => image_data.iter().flat_map(|&l| [l, l, l, 255]).collect(),
+            ColorType::Indexed => unreachable!("palettes are expanded by the decoder"),
+        };
+
+        Ok(DecodedImage {
+            width,
+            height,
+            data: ImageData::Rgba(rgba),
+        })
+    }
+}
+
+pub struct JpegDecoder;
+
+impl TextureDecoder for JpegDecoder {
+    fn extensions(&self) -> &[&str] {
+        &["jpg", "jpeg"]
+    }
+
+    fn matches(&self, data: &[u8]) -> bool {
+        data.starts_with(&[0xff, 0xd8, 0xff])
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<DecodedImage, AssetError> {
+        let mut decoder = jpeg_decoder::Decoder::new(Cursor::new(data));
+        let pixels = decoder.decode()?;
+        let info = decoder.info().unwrap();
+
+        let rgba: Vec<u8> = match info.pixel_format {
+            jpeg_decoder::PixelFormat::L8 => pixels.iter().flat_map(|&l| [l, l, l, 255]).collect(),
+            // Big endian, only the most significant byte is kept.
+            jpeg_decoder::PixelFormat::L16 => pixels
+                .chunks(2)
+                .flat_map(|l| [l[0], l[0], l[0], 255])
+                .collect(),
+            jpeg_decoder::PixelFormat::RGB24 => pixels
+                .chunks(3)
+                .flat_map(|x| [x[0], x[1], x[2], 255])
+                .collect(),
+            jpeg_decoder::PixelFormat::CMYK32 => {
+                return Err(AssetError::UnsupportedContent(
+                    "CMYK JPEG images".to_owned(),
+                ))
+            }
+        };
+
+        Ok(DecodedImage {
+            width: info.width as u32,
+            height: info.height as u32,
+            data: ImageData::Rgba(rgba),
+        })
+    }
+}
+
+/// Reads KTX2 textures that store one of the BCn formats or RGBA8, without supercompression.
+/// Basis Universal textures would first need to be transcoded, which isn't supported.
+pub struct Ktx2Decoder;
+
+impl Ktx2Decoder {
+    fn get_formats(format: ktx2::Format) -> Option<(Format, Format)> {
+        let formats = match format {
+            ktx2::Format::R8G8B8A8_UNORM | ktx2::Format::R8G8B8A8_SRGB => {
+                (Format::R8G8B8A8_UNORM, Format::R8G8B8A8_SRGB)
+            }
+            ktx2::Format::BC1_RGBA_UNORM_BLOCK | ktx2::Format::BC1_RGBA_SRGB_BLOCK => {
+                (Format::BC1_RGBA_UNORM_BLOCK, Format::BC1_RGBA_SRGB_BLOCK)
+            }
+            ktx2::Format::BC2_UNORM_BLOCK | ktx2::Format::BC2_SRGB_BLOCK => {
+                (Format::BC2_UNORM_BLOCK, Format::BC2_SRGB_BLOCK)
+            }
+            ktx2::Format::BC3_UNORM_BLOCK | ktx2::Format::BC3_SRGB_BLOCK => {
+                (Format::BC3_UNORM_BLOCK, Format::BC3_SRGB_BLOCK)
+            }
+            // Single and dual channel formats have no sRGB variant.
+            ktx2::Format::BC4_UNORM_BLOCK => (Format::BC4_UNORM_BLOCK, Format::BC4_UNORM_BLOCK),
+            ktx2::Format::BC5_UNORM_BLOCK => (Format::BC5_UNORM_BLOCK, Format::BC5_UNORM_BLOCK),
+            ktx2::Format::BC7_UNORM_BLOCK | ktx2::Format::BC7_SRGB_BLOCK => {
+                (Format::BC7_UNORM_BLOCK, Format::BC7_SRGB_BLOCK)
+            }
+            _ => return None,
+        };
+
+        Some(formats)
+    }
+}
+
+impl TextureDecoder for Ktx2Decoder {
+    fn extensions(&self) -> &[&str] {
+        &["ktx2"]
+    }
+
+    fn matches(&self, data: &[u8]) -> bool {
+        data.starts_with(&[
+            0xab, 0x4b, 0x54, 0x58, 0x20, 0x32, 0x30, 0xbb, 0x0d, 0x0a, 0x1a, 0x0a,
+        ])
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<DecodedImage, AssetError> {
+        let reader = ktx2::Reader::new(data)?;
+        let header = reader.header();
+
+        if header.supercompression_scheme.is_some() {
+            return Err(AssetError::UnsupportedContent(
+                "supercompressed KTX2 textures".to_owned(),
+            ));
+        }
+        if header.pixel_depth > 1 || header.layer_count > 1 || header.face_count > 1 {
+            return Err(AssetError::UnsupportedContent(
+                "KTX2 textures that are not a single 2D image".to_owned(),
+            ));
+        }
+
+        let (linear, srgb) = header
+            .format
+            .and_then(Ktx2Decoder::get_formats)
+            .ok_or_else(|| {
+                AssetError::UnsupportedContent(format!("KTX2 format {:?}", header.format))
+            })?;
+
+        Ok(DecodedImage {
+            width: header.pixel_width,
+            height: header.pixel_height.max(1),
+            data: ImageData::Native {
+                linear,
+                srgb,
+                levels: reader.levels().map(|level| level.to_vec()).collect(),
+            },
+        })
+    }
+}
+
+/// Converts the uncompressed texels of an embedded texture to RGBA.
+pub fn texels_to_rgba(texels: &[Texel]) -> Vec<u8> {
+    texels
+        .iter()
+        .flat_map(|texel| [texel.r, texel.g, texel.b, texel.a])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use png::BitDepth;
+
+    use super::*;
+
+    fn encode_png(width: u32, height: u32, color_type: ColorType, data: &[u8]) -> Vec<u8> {
+        encode_png_with(width, height, data, |encoder| {
+            encoder.set_color(color_type);
+            encoder.set_depth(BitDepth::Eight);
+        })
+    }
+
+    /// Encodes `data` with the color type, bit depth and palette set by `configure`.
+    fn encode_png_with(
+        width: u32,
+        height: u32,
+        data: &[u8],
+        configure: impl FnOnce(&mut png::Encoder<&mut Vec<u8>>),
+    ) -> Vec<u8> {
+        let mut bytes = vec![];
+        let mut encoder = png::Encoder::new(&mut bytes, width, height);
+        configure(&mut encoder);
+        encoder
+            .write_header()
+            .unwrap()
+            .write_image_data(data)
+            .unwrap();
+        bytes
+    }
+
+    fn decode_rgba(
+        registry: &DecoderRegistry,
+        extension: &str,
+        data: &[u8],
+    ) -> (u32, u32, Vec<u8>) {
+        let image = registry.decode(extension, data).unwrap();
+        match image.data {
+            ImageData::Rgba(rgba) => (image.width, image.height, rgba),
+            ImageData::Native { .. } => panic!("expected RGBA texels"),
+        }
+    }
+
+    /// Reads any data as a single white texel, for files with the extension "raw".
+    struct RawDecoder;
+
+    impl TextureDecoder for RawDecoder {
+        fn extensions(&self) -> &[&str] {
+            &["raw"]
+        }
+
+        fn matches(&self, _data: &[u8]) -> bool {
+            false
+        }
+
+        fn decode(&self, _data: &[u8]) -> Result<DecodedImage, AssetError> {
+            Ok(DecodedImage {
+                width: 1,
+                height: 1,
+                data: ImageData::Rgba(vec![255; 4]),
+            })
+        }
+    }
+
+    #[test]
+    fn magic_bytes_take_precedence_over_the_extension() {
+        let png = encode_png(2, 1, ColorType::Rgb, &[1, 2, 3, 4, 5, 6]);
+        let registry = DecoderRegistry::default();
+
+        let (width, height, rgba) = decode_rgba(&registry, "jpg", &png);
+        assert_eq!((width, height), (2, 1));
+        assert_eq!(rgba, vec![1, 2, 3, 255, 4, 5, 6, 255]);
+    }
+
+    #[test]
+    fn extension_is_used_without_magic_bytes() {
+        let mut registry = DecoderRegistry::default();
+        registry.register(RawDecoder);
+
+        assert_eq!(decode_rgba(&registry, "RAW", b"data").2, vec![255; 4]);
+        assert!(matches!(
+            registry.decode("bmp", b"data"),
+            Err(AssetError::UnsupportedFormat(extension)) if extension == "bmp"
+        ));
+        // The extension picks the PNG decoder, which rejects the data.
+        assert!(matches!(
+            registry.decode("png", b"data"),
+            Err(AssetError::Png(_))
+        ));
+    }
+
+    fn decode_png(png: &[u8]) -> (u32, u32, Vec<u8>) {
+        decode_rgba(&DecoderRegistry::default(), "png", png)
+    }
+
+    #[test]
+    fn gray_png_is_expanded_to_rgba() {
+        let png = encode_png(2, 2, ColorType::Grayscale, &[0, 64, 128, 255]);
+        let rgba = [
+            [0, 0, 0, 255],
+            [64, 64, 64, 255],
+            [128, 128, 128, 255],
+            [255, 255, 255, 255],
+        ]
+        .concat();
+        assert_eq!(decode_png(&png), (2, 2, rgba));
+    }
+
+    #[test]
+    fn gray_alpha_png_is_expanded_to_rgba() {
+        let png = encode_png(2, 1, ColorType::GrayscaleAlpha, &[10, 20, 30, 40]);
+        let rgba = vec![10, 10, 10, 20, 30, 30, 30, 40];
+        assert_eq!(decode_png(&png), (2, 1, rgba));
+    }
+
+    #[test]
+    fn palette_png_is_expanded_to_rgba() {
+        // Two bits per texel, where the transparency chunk only covers the first entry.
+        let png = encode_png_with(4, 1, &[0b00_01_10_01], |encoder| {
+            encoder.set_color(ColorType::Indexed);
+            encoder.set_depth(BitDepth::Two);
+            encoder.set_palette(vec![255, 0, 0, 0, 255, 0, 0, 0, 255]);
+            encoder.set_trns(vec![128]);
+        });
+        let rgba = [
+            [255, 0, 0, 128],
+            [0, 255, 0, 255],
+            [0, 0, 255, 255],
+            [0, 255, 0, 255],
+        ]
+        .concat();
+        assert_eq!(decode_png(&png), (4, 1, rgba));
+    }
+
+    #[test]
+    fn low_bit_depth_gray_png_is_scaled_to_8_bits() {
+        let png = encode_png_with(8, 1, &[0b1010_0000], |encoder| {
+            encoder.set_color(ColorType::Grayscale);
+            encoder.set_depth(BitDepth::One);
+        });
+        let (width, height, rgba) = decode_png(&png);
+        assert_eq!((width, height), (8, 1));
+        assert_eq!(
+            &rgba[..12],
+            &[255, 255, 255, 255, 0, 0, 0, 255, 255, 255, 255, 255]
+        );
+    }
+
+    #[test]
+    fn sixteen_bit_png_keeps_the_most_significant_byte() {
+        // Big endian channels.
+        let data = [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xff, 0x00];
+        let png = encode_png_with(1, 1, &data, |encoder| {
+            encoder.set_color(ColorType::Rgba);
+            encoder.set_depth(BitDepth::Sixteen);
+        });
+        assert_eq!(decode_png(&png), (1, 1, vec![0x12, 0x56, 0x9a, 0xff]));
+    }
+
+    #[test]
+    fn texels_are_reordered_to_rgba() {
+        let texels = [
+            Texel {
+                r: 1,
+                g: 2,
+                b: 3,
+                a: 4,
+            },
+            Texel {
+                r: 5,
+                g: 6,
+                b: 7,
+                a: 8,
+            },
+        ];
+        assert_eq!(texels_to_rgba(&texels), vec![1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+}
@@ -1,7 +1,11 @@
 use crate::atlas_core::SystemInfo;
 use std::sync::Arc;
 
-use egui::{epaint::ClippedShape, TextStyle, Ui};
+use egui::{
+    epaint::ClippedShape,
+    plot::{Legend, Line, Plot, Value, Values},
+    TextStyle, Ui,
+};
 use egui_vulkano::UpdateTexturesResult;
 use egui_winit::State;
 use vulkano::{
@@ -14,7 +18,17 @@ use vulkano::{
 use winit::window::Window;
 
 use super::{
-    renderer::deferred::{DebugPreviewBuffer, RendererParams},
+    bookmarks::CameraBookmarks,
+    camera::{get_controller, Camera, CameraController, ControllerType, Projection},
+    loader::AssetLoader,
+    renderer::{
+        deferred::{DebugPreviewBuffer, RendererParams},
+        light::{Light, LightType},
+        shadow::MAX_CASCADES,
+    },
+    scene::Scene,
+    texture::SamplerSettings,
+    timing::GpuPass,
     PerformanceInfo, System,
 };
 
@@ -77,6 +91,357 @@ fn preview_type_checkbox_item(
     ui.selectable_value(value, item, item.get_text())
 }
 
+fn vector_drag_values(ui: &mut Ui, label: &str, value: &mut [f32; 3], speed: f32) {
+    ui.horizontal(|ui| {
+        ui.label(label);
+        for component in value.iter_mut() {
+            ui.add(egui::DragValue::new(component).speed(speed));
+        }
+    });
+}
+
+fn light_editor(ui: &mut Ui, light: &mut Light) {
+    egui::ComboBox::from_label("Type")
+        .selected_text(light.light_type.get_text())
+        .show_ui(ui, |ui| {
+            for light_type in [LightType::Point, LightType::Spot] {
+                ui.selectable_value(&mut light.light_type, light_type, light_type.get_text());
+            }
+        });
+
+    vector_drag_values(ui, "Position", &mut light.position, 1.0);
+
+    ui.horizontal(|ui| {
+        ui.label("Color");
+        ui.color_edit_button_rgb(&mut light.color);
+    });
+    ui.add(egui::Slider::new(&mut light.intensity, 0.0..=10.0).text("Intensity"));
+    ui.add(
+        egui::Slider::new(&mut light.range, 1.0..=5000.0)
+            .logarithmic(true)
+            .text("Range"),
+    );
+
+    if light.light_type == LightType::Spot {
+        vector_drag_values(ui, "Direction", &mut light.direction, 0.01);
+
+        ui.horizontal(|ui| {
+            ui.label("Inner cone angle");
+            ui.drag_angle(&mut light.inner_cone_angle);
+        });
+        ui.horizontal(|ui| {
+            ui.label("Outer cone angle");
+            ui.drag_angle(&mut light.outer_cone_angle);
+        });
+        light.inner_cone_angle = light.inner_cone_angle.clamp(0.0, light.outer_cone_angle);
+    }
+}
+
+fn lights_editor(ui: &mut Ui, lights: &mut Vec<Light>) {
+    let mut removed = None;
+
+    for (i, light) in lights.iter_mut().enumerate() {
+        egui::CollapsingHeader::new(format!("{} light {}", light.light_type.get_text(), i))
+            .id_source(i)
+            .show(ui, |ui| {
+                light_editor(ui, light);
+
+                if ui.button("Remove").clicked() {
+                    removed = Some(i);
+                }
+            });
+    }
+
+    if let Some(i) = removed {
+        lights.remove(i);
+    }
+
+    ui.horizontal(|ui| {
+        if ui.button("Add point light").clicked() {
+            lights.push(Light::new(LightType::Point));
+        }
+        if ui.button("Add spot light").clicked() {
+            lights.push(Light::new(LightType::Spot));
+        }
+    });
+}
+
+fn shadows_editor(ui: &mut Ui, params: &mut RendererParams) {
+    egui::ComboBox::from_label("Resolution")
+        .selected_text(params.shadow_resolution.to_string())
+        .show_ui(ui, |ui| {
+            for resolution in [512, 1024, 2048, 4096] {
+                ui.selectable_value(
+                    &mut params.shadow_resolution,
+                    resolution,
+                    resolution.to_string(),
+                );
+            }
+        });
+
+    ui.add(egui::Slider::new(&mut params.shadow_bias, 0.0..=0.01).text("Bias"));
+    ui.add(egui::Slider::new(&mut params.cascade_count, 1..=MAX_CASCADES as u32).text("Cascades"));
+    ui.add(
+        egui::Slider::new(&mut params.shadow_distance, 100.0..=10000.0)
+            .logarithmic(true)
+            .text("Distance"),
+    );
+}
+
+fn textures_editor(ui: &mut Ui, sampler: &mut SamplerSettings) {
+    ui.checkbox(&mut sampler.trilinear, "Trilinear filtering");
+
+    let mut anisotropic = sampler.anisotropy.is_some();
+    ui.checkbox(&mut anisotropic, "Anisotropic filtering");
+    match (anisotropic, &mut sampler.anisotropy) {
+        (true, Some(anisotropy)) => {
+            ui.add(egui::Slider::new(anisotropy, 1.0..=16.0).text("Max anisotropy"));
+        }
+        (true, None) => sampler.anisotropy = SamplerSettings::default().anisotropy,
+        (false, _) => sampler.anisotropy = None,
+    }
+}
+
+/// Lists the loaded models, and the whole scene, to fit the view to.
+fn frame_menu(
+    ui: &mut Ui,
+    camera: &mut Camera,
+    controller: &mut dyn CameraController,
+    scene: &Scene,
+) {
+    let models = &scene.nodes[scene.root].children;
+    let targets = std::iter::once((scene.root, "Whole scene".to_owned())).chain(
+        models
+            .iter()
+            .enumerate()
+            .map(|(i, &node)| match scene.nodes[node].name.as_str() {
+                "" => (node, format!("Model {}", i)),
+                name => (node, name.to_owned()),
+            }),
+    );
+
+    ui.menu_button("Frame selected", |ui| {
+        for (node, name) in targets {
+            if ui.button(name).clicked() {
+                if let Some(bounds) = scene.get_bounds(node) {
+                    controller.frame(camera, &bounds);
+                }
+                ui.close_menu();
+            }
+        }
+    });
+}
+
+fn camera_editor(
+    ui: &mut Ui,
+    camera: &mut Camera,
+    controller: &mut Box<dyn CameraController>,
+    scene: &Scene,
+) {
+    let controller_type = controller.get_type();
+    egui::ComboBox::from_label("Controller")
+        .selected_text(controller_type.get_text())
+        .show_ui(ui, |ui| {
+            for new_type in [ControllerType::Fly, ControllerType::Orbit] {
+                if ui
+                    .selectable_label(controller_type == new_type, new_type.get_text())
+                    .clicked()
+                    && controller_type != new_type
+                {
+                    *controller = get_controller(new_type, camera);
+                }
+            }
+        });
+    frame_menu(ui, camera, controller.as_mut(), scene);
+
+    egui::ComboBox::from_label("Projection")
+        .selected_text(camera.projection.get_text())
+        .show_ui(ui, |ui| {
+            for projection in [Projection::Perspective, Projection::Orthographic] {
+                ui.selectable_value(&mut camera.projection, projection, projection.get_text());
+            }
+        });
+
+    match camera.projection {
+        Projection::Perspective => {
+            ui.add(
+                egui::Slider::new(&mut camera.fov_y.0, 10.0..=150.0)
+                    .suffix("°")
+                    .text("Vertical FOV"),
+            );
+        }
+        Projection::Orthographic => {
+            ui.add(
+                egui::Slider::new(&mut camera.ortho_height, 1.0..=10000.0)
+                    .logarithmic(true)
+                    .text("Height"),
+            );
+        }
+    }
+
+    ui.add(
+        egui::Slider::new(&mut camera.near, 0.01..=100.0)
+            .logarithmic(true)
+            .text("Near"),
+    );
+    let infinite_far = camera.reverse_z && camera.projection == Projection::Perspective;
+    ui.add_enabled(
+        !infinite_far,
+        egui::Slider::new(&mut camera.far, 100.0..=100000.0)
+            .logarithmic(true)
+            .text("Far"),
+    );
+    camera.far = camera.far.max(camera.near * 2.0);
+
+    ui.checkbox(&mut camera.reverse_z, "Reverse Z");
+
+    let movement = &mut camera.movement;
+    ui.add(
+        egui::Slider::new(&mut movement.speed, 1.0..=5000.0)
+            .logarithmic(true)
+            .text("Speed"),
+    );
+    ui.add(egui::Slider::new(&mut movement.boost, 1.0..=20.0).text("Boost"));
+    ui.add(
+        egui::Slider::new(&mut movement.acceleration, 0.5..=100.0)
+            .logarithmic(true)
+            .text("Acceleration"),
+    );
+    ui.add(
+        egui::Slider::new(&mut movement.damping, 0.5..=100.0)
+            .logarithmic(true)
+            .text("Damping"),
+    );
+}
+
+/// Timings of the last frame, and a graph of the time of every pass over the recent frames.
+fn gpu_timings_view(ui: &mut Ui, performance_info: &PerformanceInfo) {
+    let timings = &performance_info.gpu_timings;
+    let last = match timings.back() {
+        Some(last) => last,
+        None => {
+            ui.label("No GPU timings");
+            return;
+        }
+    };
+
+    for pass in GpuPass::ALL {
+        ui.label(format!("{}: {:.2} ms", pass.get_text(), last.get(pass)));
+    }
+    ui.label(format!("Total: {:.2} ms", last.total()));
+
+    let line = |name: &str, get: &dyn Fn(usize) -> f32| {
+        let values = (0..timings.len()).map(|i| Value::new(i as f64, get(i) as f64));
+        Line::new(Values::from_values_iter(values)).name(name)
+    };
+
+    Plot::new("gpu_timings")
+        .height(150.0)
+        .include_y(0.0)
+        .allow_drag(false)
+        .allow_zoom(false)
+        .legend(Legend::default())
+        .show(ui, |plot_ui| {
+            for pass in GpuPass::ALL {
+                plot_ui.line(line(pass.get_text(), &|i| timings[i].get(pass)));
+            }
+            plot_ui.line(line("Total", &|i| timings[i].total()));
+        });
+}
+
+enum BookmarkAction {
+    GoTo(usize),
+    Update(usize),
+    RemoveBookmark(usize),
+    Play(usize),
+    RemovePath(usize),
+    Renamed,
+}
+
+fn bookmarks_editor(
+    ui: &mut Ui,
+    bookmarks: &mut CameraBookmarks,
+    camera: &mut Camera,
+    controller: &mut Box<dyn CameraController>,
+) {
+    let mut action = None;
+
+    for (i, bookmark) in bookmarks.file.bookmarks.iter_mut().enumerate() {
+        ui.horizontal(|ui| {
+            // Only the first nine can be recalled with the number keys.
+            ui.label(if i < 9 {
+                format!("{}", i + 1)
+            } else {
+                "-".to_owned()
+            });
+            if ui.text_edit_singleline(&mut bookmark.name).lost_focus() {
+                action = Some(BookmarkAction::Renamed);
+            }
+            if ui.button("Go").clicked() {
+                action = Some(BookmarkAction::GoTo(i));
+            }
+            if ui.button("Update").clicked() {
+                action = Some(BookmarkAction::Update(i));
+            }
+            if ui.button("Remove").clicked() {
+                action = Some(BookmarkAction::RemoveBookmark(i));
+            }
+        });
+    }
+    if ui.button("Add bookmark").clicked() {
+        let name = format!("Bookmark {}", bookmarks.file.bookmarks.len() + 1);
+        bookmarks.add_bookmark(name, camera);
+    }
+
+    ui.separator();
+
+    for (i, path) in bookmarks.file.paths.iter_mut().enumerate() {
+        ui.horizontal(|ui| {
+            if ui.text_edit_singleline(&mut path.name).lost_focus() {
+                action = Some(BookmarkAction::Renamed);
+            }
+            ui.label(format!("{:.1} s", path.duration()));
+            if ui.button("Play").clicked() {
+                action = Some(BookmarkAction::Play(i));
+            }
+            if ui.button("Remove").clicked() {
+                action = Some(BookmarkAction::RemovePath(i));
+            }
+        });
+    }
+
+    if let Some(recording) = &bookmarks.recording {
+        let label = format!("Stop recording ({:.1} s)", recording.time);
+        if ui.button(label).clicked() {
+            let name = format!("Path {}", bookmarks.file.paths.len() + 1);
+            bookmarks.stop_recording(name, camera);
+        }
+    } else if bookmarks.playback.is_some() {
+        if ui.button("Stop playback").clicked() {
+            bookmarks.playback = None;
+        }
+    } else if ui.button("Record path").clicked() {
+        bookmarks.start_recording();
+    }
+
+    match action {
+        Some(BookmarkAction::GoTo(i)) => bookmarks.go_to(i, camera, controller),
+        Some(BookmarkAction::Update(i)) => bookmarks.set_bookmark(i, camera),
+        Some(BookmarkAction::RemoveBookmark(i)) => {
+            bookmarks.file.bookmarks.remove(i);
+            bookmarks.save();
+        }
+        Some(BookmarkAction::Play(i)) => bookmarks.play(i),
+        Some(BookmarkAction::RemovePath(i)) => {
+            bookmarks.playback = None;
+            bookmarks.file.paths.remove(i);
+            bookmarks.save();
+        }
+        Some(BookmarkAction::Renamed) => bookmarks.save(),
+        None => {}
+    }
+}
+
 pub fn update_textures_egui(
     performance_info: &PerformanceInfo,
     system_info: &SystemInfo,
@@ -86,6 +451,11 @@ pub fn update_textures_egui(
     egui_painter: &mut egui_vulkano::Painter,
     egui_winit: &mut State,
     params: &mut RendererParams,
+    camera: &mut Camera,
+    controller: &mut Box<dyn CameraController>,
+    scene: &Scene,
+    bookmarks: &mut CameraBookmarks,
+    loader: &AssetLoader,
 ) -> (Vec<ClippedShape>, bool) {
     egui_ctx.begin_frame(egui_winit.take_egui_input(surface.window()));
 
@@ -96,6 +466,34 @@ pub fn update_textures_egui(
             "delta time: {:.2} ms",
             performance_info.delta_time_ms
         ));
+        egui::CollapsingHeader::new("GPU timings").show(ui, |ui| {
+            gpu_timings_view(ui, performance_info);
+        });
+        ui.end_row();
+
+        ui.label(format!(
+            "meshes drawn: {}, culled: {}",
+            performance_info.culling.drawn, performance_info.culling.culled
+        ));
+
+        if loader.is_loading() {
+            ui.label("Loading assets");
+            ui.add(egui::ProgressBar::new(loader.progress.fraction()).show_percentage());
+            ui.end_row();
+        }
+        ui.label(format!("Textures: {}", loader.cache.len()));
+        ui.end_row();
+
+        if !loader.errors.is_empty() {
+            egui::CollapsingHeader::new(format!("Asset errors ({})", loader.errors.len()))
+                .default_open(true)
+                .show(ui, |ui| {
+                    for error in &loader.errors {
+                        ui.colored_label(egui::Color32::RED, error.to_string());
+                    }
+                });
+            ui.end_row();
+        }
 
         ui.label("Ambient light color");
         ui.color_edit_button_rgba_unmultiplied(&mut params.ambient_color);
@@ -105,6 +503,31 @@ pub fn update_textures_egui(
         ui.color_edit_button_rgba_unmultiplied(&mut params.directional_color);
         ui.end_row();
 
+        egui::CollapsingHeader::new("Camera").show(ui, |ui| {
+            camera_editor(ui, camera, controller, scene);
+        });
+        ui.end_row();
+
+        egui::CollapsingHeader::new("Bookmarks").show(ui, |ui| {
+            bookmarks_editor(ui, bookmarks, camera, controller);
+        });
+        ui.end_row();
+
+        egui::CollapsingHeader::new("Lights").show(ui, |ui| {
+            lights_editor(ui, &mut params.lights);
+        });
+        ui.end_row();
+
+        egui::CollapsingHeader::new("Shadows").show(ui, |ui| {
+            shadows_editor(ui, params);
+        });
+        ui.end_row();
+
+        egui::CollapsingHeader::new("Textures").show(ui, |ui| {
+            textures_editor(ui, &mut params.sampler);
+        });
+        ui.end_row();
+
         egui::ComboBox::from_label("Preview")
             .selected_text(params.preview_buffer.get_text())
             .show_ui(ui, |ui| {
@@ -0,0 +1,75 @@
+use std::{error::Error, fmt, io, path::PathBuf, sync::Arc};
+
+use russimp::RussimpError;
+
+#[derive(Debug)]
+pub enum AssetError {
+    /// The file does not exist or could not be read.
+    Io { path: PathBuf, error: io::Error },
+    /// Assimp could not import the model.
+    Import { path: PathBuf, error: RussimpError },
+    /// The data is not a valid PNG image.
+    Png(png::DecodingError),
+    /// The data is not a valid JPEG image.
+    Jpeg(jpeg_decoder::Error),
+    /// The data is not a valid KTX2 texture.
+    Ktx2(ktx2::ParseError),
+    /// No decoder recognizes the texture, given by its extension or format hint.
+    UnsupportedFormat(String),
+    /// The format of the texture is recognized, but it uses a feature that isn't supported.
+    UnsupportedContent(String),
+    /// An embedded texture has neither a path nor any data.
+    MissingTextureData,
+    /// A texture of a material could not be loaded and was replaced by a fallback. The error is
+    /// shared by all materials using the texture.
+    Texture {
+        name: String,
+        error: Arc<AssetError>,
+    },
+}
+
+impl fmt::Display for AssetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssetError::Io { path, error } => {
+                write!(f, "Could not read {}: {}", path.display(), error)
+            }
+            AssetError::Import { path, error } => {
+                write!(f, "Could not import {}: {:?}", path.display(), error)
+            }
+            AssetError::Png(error) => write!(f, "Invalid PNG image: {}", error),
+            AssetError::Jpeg(error) => write!(f, "Invalid JPEG image: {}", error),
+            AssetError::Ktx2(error) => write!(f, "Invalid KTX2 texture: {:?}", error),
+            AssetError::UnsupportedFormat(hint) => {
+                write!(f, "Texture has unsupported format '{}'", hint)
+            }
+            AssetError::UnsupportedContent(content) => {
+                write!(f, "Unsupported texture content: {}", content)
+            }
+            AssetError::MissingTextureData => write!(f, "Embedded texture has no data"),
+            AssetError::Texture { name, error } => {
+                write!(f, "Could not load texture {}: {}", name, error)
+            }
+        }
+    }
+}
+
+impl Error for AssetError {}
+
+impl From<png::DecodingError> for AssetError {
+    fn from(error: png::DecodingError) -> Self {
+        AssetError::Png(error)
+    }
+}
+
+impl From<jpeg_decoder::Error> for AssetError {
+    fn from(error: jpeg_decoder::Error) -> Self {
+        AssetError::Jpeg(error)
+    }
+}
+
+impl From<ktx2::ParseError> for AssetError {
+    fn from(error: ktx2::ParseError) -> Self {
+        AssetError::Ktx2(error)
+    }
+}
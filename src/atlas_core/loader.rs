@@ -0,0 +1,132 @@
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
+    thread,
+};
+
+use vulkano::device::Queue;
+
+use crate::atlas_core::error::AssetError;
+use crate::atlas_core::mesh::load_gltf;
+use crate::atlas_core::scene::Scene;
+use crate::atlas_core::texture::TextureCache;
+
+/// Counts the parts (materials and meshes) of models that have been loaded so far.
+#[derive(Default)]
+pub struct LoadProgress {
+    done: AtomicUsize,
+    total: AtomicUsize,
+}
+
+impl LoadProgress {
+    pub fn add_work(&self, amount: usize) {
+        self.total.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    pub fn finish_work(&self, amount: usize) {
+        self.done.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    /// Between 0 and 1, or 0 while no model has been imported yet.
+    pub fn fraction(&self) -> f32 {
+        let total = self.total.load(Ordering::Relaxed);
+        if total == 0 {
+            return 0.0;
+        }
+
+        self.done.load(Ordering::Relaxed) as f32 / total as f32
+    }
+}
+
+struct LoadedModel {
+    result: Result<Scene, AssetError>,
+    errors: Vec<AssetError>,
+}
+
+/// Loads models on worker threads, so the application stays responsive in the meantime.
+pub struct AssetLoader {
+    pub progress: Arc<LoadProgress>,
+    /// Shared by all models, so textures used by multiple models are only loaded once.
+    pub cache: Arc<TextureCache>,
+    /// Errors of all models loaded so far.
+    pub errors: Vec<AssetError>,
+    pending: usize,
+    sender: Sender<LoadedModel>,
+    receiver: Receiver<LoadedModel>,
+}
+
+impl AssetLoader {
+    pub fn new() -> AssetLoader {
+        let (sender, receiver) = channel();
+
+        AssetLoader {
+            progress: Arc::new(LoadProgress::default()),
+            cache: Arc::new(TextureCache::default()),
+            errors: vec![],
+            pending: 0,
+            sender,
+            receiver,
+        }
+    }
+
+    /// Starts loading the model at `path` in the background.
+    ///
+    /// Textures and meshes are uploaded using `queue`, which should preferably be a transfer queue
+    /// that isn't used for rendering. The renderer copies the meshes into the buffers of the scene
+    /// once the model is added to it, see `Scene::upload`.
+    pub fn load(&mut self, queue: &Arc<Queue>, path: &str) {
+        let queue = queue.clone();
+        let path = path.to_owned();
+        let sender = self.sender.clone();
+        let progress = self.progress.clone();
+        let cache = self.cache.clone();
+
+        self.pending += 1;
+
+        thread::spawn(move || {
+            let mut errors = vec![];
+            let result = load_gltf(&queue, Path::new(&path), &mut errors, &progress, &cache);
+
+            // The receiver only goes away when the application exits.
+            let _ = sender.send(LoadedModel { result, errors });
+        });
+    }
+
+    /// Returns the models that finished loading since the last call.
+    pub fn poll(&mut self) -> Vec<Scene> {
+        let mut models = vec![];
+
+        for loaded in self.receiver.try_iter() {
+            self.pending -= 1;
+
+            for error in &loaded.errors {
+                eprintln!("{}", error);
+            }
+            self.errors.extend(loaded.errors);
+
+            match loaded.result {
+                Ok(model) => models.push(model),
+                Err(error) => {
+                    eprintln!("{}", error);
+                    self.errors.push(error);
+                }
+            }
+        }
+
+        models
+    }
+
+    pub fn is_loading(&self) -> bool {
+        self.pending > 0
+    }
+}
+
+impl Default for AssetLoader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,28 +1,32 @@
-use crate::atlas_core::texture::get_descriptor_set;
-use crate::atlas_core::texture::load_png;
-use crate::atlas_core::texture::load_png_file;
-use crate::atlas_core::System;
-use crate::CpuAccessibleBuffer;
-use crate::PersistentDescriptorSet;
+use crate::atlas_core::bounds::{Aabb, BoundingSphere};
+use crate::atlas_core::decoder::{texels_to_rgba, DecodedImage, ImageData};
+use crate::atlas_core::error::AssetError;
+use crate::atlas_core::loader::LoadProgress;
+use crate::atlas_core::renderer::deferred::deferred_frag_mod;
+use crate::atlas_core::scene::{NodeId, Scene};
+use crate::atlas_core::texture::load_image;
+use crate::atlas_core::texture::load_image_file;
+use crate::atlas_core::texture::{hash_data, TextureCache, TextureSource};
 use bytemuck::{Pod, Zeroable};
-use cgmath::Matrix4;
-use russimp::scene::{PostProcess, Scene};
+use cgmath::{InnerSpace, Matrix4, Point3, Vector3};
+use russimp::material::PropertyTypeInfo;
+use russimp::node::Node;
+use russimp::scene::PostProcess;
 use russimp::texture::DataContent;
 use russimp::texture::TextureType;
+use russimp::Matrix4x4;
+use russimp::Vector3D;
+use std::fs;
 use std::path::Path;
 use std::sync::Arc;
-use vulkano::buffer::BufferUsage;
-use vulkano::buffer::TypedBufferAccess;
-use vulkano::command_buffer::AutoCommandBufferBuilder;
+use std::thread;
 use vulkano::command_buffer::CommandBufferExecFuture;
 use vulkano::command_buffer::PrimaryAutoCommandBuffer;
-use vulkano::descriptor_set::layout::DescriptorSetLayout;
+use vulkano::device::Queue;
+use vulkano::format::Format;
 use vulkano::image::view::ImageView;
 use vulkano::image::ImmutableImage;
 use vulkano::impl_vertex;
-use vulkano::pipeline::GraphicsPipeline;
-use vulkano::pipeline::Pipeline;
-use vulkano::pipeline::PipelineBindPoint;
 use vulkano::sync::NowFuture;
 
 #[repr(C)]
@@ -32,36 +36,33 @@ pub struct Vertex2D {
 }
 impl_vertex!(Vertex2D, position);
 
+/// The attributes of a mesh vertex, interleaved in a single buffer. Pipelines that draw meshes
+/// derive their vertex input from this type, and may use only some of its attributes.
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Default, Zeroable, Pod)]
 pub struct Vertex {
     pub position: [f32; 3],
-}
-
-impl_vertex!(Vertex, position);
-
-#[repr(C)]
-#[derive(Clone, Copy, Debug, Default, Zeroable, Pod)]
-pub struct Normal {
     pub normal: [f32; 3],
-}
-
-impl_vertex!(Normal, normal);
-
-#[repr(C)]
-#[derive(Clone, Copy, Debug, Default, Zeroable, Pod)]
-pub struct TexCoord {
     pub tex_coord: [f32; 2],
+    /// The w component is -1 if the bitangent is `cross(tangent, normal)` instead of
+    /// `cross(normal, tangent)`.
+    pub tangent: [f32; 4],
 }
 
-impl_vertex!(TexCoord, tex_coord);
+impl_vertex!(Vertex, position, normal, tex_coord, tangent);
 
+/// A range of the vertices and indices of a scene, drawn with a single material.
+#[derive(Clone, Copy)]
 pub struct MeshBuffer {
-    pub vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>>,
-    pub normal_buffer: Arc<CpuAccessibleBuffer<[Normal]>>,
-    pub index_buffer: Arc<CpuAccessibleBuffer<[u32]>>,
-    pub tex_coord_buffer: Arc<CpuAccessibleBuffer<[TexCoord]>>,
-    pub material: Material,
+    pub first_index: u32,
+    pub index_count: u32,
+    /// Added to every index of the mesh.
+    pub vertex_offset: i32,
+    /// Index into `Scene::materials`.
+    pub material: usize,
+    /// Bounds of the vertices, before they are transformed by the node that draws them.
+    pub aabb: Aabb,
+    pub sphere: BoundingSphere,
 }
 
 pub struct Texture {
@@ -69,199 +70,431 @@ pub struct Texture {
     pub future: CommandBufferExecFuture<NowFuture, PrimaryAutoCommandBuffer>,
 }
 
+#[derive(Clone)]
 pub struct Material {
-    pub uniform_set: Option<Arc<PersistentDescriptorSet>>,
+    /// The base color, metallic-roughness, occlusion, emissive and normal texture.
+    pub textures: [Arc<ImageView<ImmutableImage>>; 5],
+    /// The texture indices are assigned when the scene is uploaded, see `Scene::upload`.
+    pub data: deferred_frag_mod::ty::MaterialData,
 }
 
-pub struct Mesh {
-    pub mesh_buffers: Vec<MeshBuffer>,
-    pub materials: Vec<Material>,
-    pub model_matrix: Matrix4<f32>,
+/// glTF defines missing textures as white, so only the factors of the material remain.
+const WHITE: [u8; 4] = [255, 255, 255, 255];
+/// Normal pointing straight out of the surface, which leaves the normals of the mesh unchanged.
+const FLAT_NORMAL: [u8; 4] = [128, 128, 255, 255];
+/// Replaces base color textures that could not be loaded, so they stand out.
+const MAGENTA: [u8; 4] = [255, 0, 255, 255];
+
+fn load_embedded_texture(
+    queue: &Arc<Queue>,
+    assimp_texture: &russimp::texture::Texture,
+    format: Format,
+    cache: &TextureCache,
+) -> Result<Texture, AssetError> {
+    let image = match &assimp_texture.data {
+        Some(DataContent::Bytes(bytes)) => cache
+            .decoders
+            .decode(&assimp_texture.ach_format_hint, bytes)?,
+        // Uncompressed textures have their size set, instead of a format hint.
+        Some(DataContent::Texel(texels)) => DecodedImage {
+            width: assimp_texture.width,
+            height: assimp_texture.height,
+            data: ImageData::Rgba(texels_to_rgba(texels)),
+        },
+        None => return Err(AssetError::MissingTextureData),
+    };
+
+    load_image(queue, image, format)
 }
 
-fn load_default_texture(system: &System) -> Texture {
-    load_png_file(
-        &system.queue,
-        "assets/models/sponza/16011208436118768083.png",
-    )
+fn embedded_texture_source(assimp_texture: &russimp::texture::Texture) -> TextureSource {
+    match &assimp_texture.data {
+        Some(DataContent::Bytes(bytes)) => TextureSource::Embedded(hash_data(bytes)),
+        Some(DataContent::Texel(texels)) => {
+            TextureSource::Embedded(hash_data(&texels_to_rgba(texels)))
+        }
+        // This can't be loaded anyway, so it doesn't matter that these share an entry.
+        None => TextureSource::Embedded(0),
+    }
 }
 
-pub fn load_material(
-    system: &System,
-    layout: &Arc<DescriptorSetLayout>,
+/// Loads the first texture of the material that has one of `texture_types`, or takes it from
+/// `cache` if another material already loaded it.
+fn load_texture(
+    queue: &Arc<Queue>,
     assimp_material: &russimp::material::Material,
+    texture_types: &[TextureType],
     base_dir: &str,
-) -> Material {
-    let base_textures = assimp_material.textures.get(&TextureType::BaseColor);
+    format: Format,
+    cache: &TextureCache,
+) -> Option<Result<Arc<ImageView<ImmutableImage>>, AssetError>> {
+    let assimp_texture = texture_types
+        .iter()
+        .filter_map(|texture_type| assimp_material.textures.get(texture_type))
+        .find_map(|textures| textures.first())?;
 
-    let result_tex = if base_textures.is_some() {
-        let assimp_texture = &base_textures.unwrap().first().unwrap();
+    let (name, texture) = if assimp_texture.path != "" {
+        let abs_tex_path = base_dir.to_owned() + assimp_texture.path.as_str();
+        // Different materials may refer to the same file by different relative paths.
+        let source =
+            fs::canonicalize(&abs_tex_path).unwrap_or_else(|_| abs_tex_path.clone().into());
+        let texture = cache.get_or_load(TextureSource::File(source), format, || {
+            load_image_file(queue, &cache.decoders, Path::new(&abs_tex_path), format)
+        });
+        (abs_tex_path, texture)
+    } else {
+        let source = embedded_texture_source(assimp_texture);
+        let texture = cache.get_or_load(source, format, || {
+            load_embedded_texture(queue, assimp_texture, format, cache)
+        });
+        ("embedded texture".to_owned(), texture)
+    };
 
-        let texture = if assimp_texture.path != "" {
-            let abs_tex_path = base_dir.to_owned() + assimp_texture.path.as_str();
-            load_png_file(&system.queue, &abs_tex_path)
-        } else {
-            assert_eq!(
-                assimp_texture.ach_format_hint, "png",
-                "Encompassed texture data should be in png format"
-            );
-
-            match assimp_texture
-                .data
-                .as_ref()
-                .expect("Unexpected texture data")
-            {
-                DataContent::Texel(_) => panic!("Loading textures by texels is not yet supported"),
-                DataContent::Bytes(bytes) => load_png(&system.queue, bytes),
+    Some(texture.map_err(|error| AssetError::Texture { name, error }))
+}
+
+/// Reads the first material property named one of `keys`, which differ between assimp versions.
+fn get_factor<const N: usize>(
+    assimp_material: &russimp::material::Material,
+    keys: &[&str],
+    semantic: TextureType,
+    default: [f32; N],
+) -> [f32; N] {
+    assimp_material
+        .properties
+        .iter()
+        .filter(|property| property.semantic == semantic)
+        .find(|property| keys.contains(&property.key.as_str()))
+        .and_then(|property| match &property.data {
+            PropertyTypeInfo::FloatArray(values) if values.len() >= N => {
+                values[..N].try_into().ok()
             }
-        };
+            _ => None,
+        })
+        .unwrap_or(default)
+}
 
-        Some(texture)
-    } else {
-        None
+pub fn load_material(
+    queue: &Arc<Queue>,
+    assimp_material: &russimp::material::Material,
+    base_dir: &str,
+    errors: &mut Vec<AssetError>,
+    cache: &TextureCache,
+) -> Material {
+    let mut load = |texture_types: &[TextureType], format, missing, broken| match load_texture(
+        queue,
+        assimp_material,
+        texture_types,
+        base_dir,
+        format,
+        cache,
+    ) {
+        Some(Ok(texture)) => texture,
+        Some(Err(error)) => {
+            errors.push(error);
+            cache.get_solid_color(queue, broken, format)
+        }
+        None => cache.get_solid_color(queue, missing, format),
     };
 
-    let uniform_set = match result_tex {
-        None => get_descriptor_set(system, layout, load_default_texture(system)),
-        Some(x) => get_descriptor_set(system, layout, x),
+    let base_color = load(
+        &[TextureType::BaseColor, TextureType::Diffuse],
+        Format::R8G8B8A8_SRGB,
+        WHITE,
+        MAGENTA,
+    );
+    // Assimp exposes the combined glTF metallic-roughness texture under several types.
+    // Like normal maps, it stores data rather than colors, so it must not be converted from sRGB.
+    let metallic_roughness = load(
+        &[
+            TextureType::Metalness,
+            TextureType::Roughness,
+            TextureType::Unknown,
+        ],
+        Format::R8G8B8A8_UNORM,
+        WHITE,
+        WHITE,
+    );
+    let occlusion = load(
+        &[TextureType::AmbientOcclusion, TextureType::LightMap],
+        Format::R8G8B8A8_UNORM,
+        WHITE,
+        WHITE,
+    );
+    let emissive = load(
+        &[TextureType::Emissive, TextureType::EmissionColor],
+        Format::R8G8B8A8_SRGB,
+        WHITE,
+        WHITE,
+    );
+    // Normal maps store vectors, which must not be converted from sRGB.
+    let normal = load(
+        &[TextureType::Normals],
+        Format::R8G8B8A8_UNORM,
+        FLAT_NORMAL,
+        FLAT_NORMAL,
+    );
+
+    let [metallic_factor] = get_factor(
+        assimp_material,
+        &[
+            "$mat.metallicFactor",
+            "$mat.gltf.pbrMetallicRoughness.metallicFactor",
+        ],
+        TextureType::None,
+        [1.0],
+    );
+    let [roughness_factor] = get_factor(
+        assimp_material,
+        &[
+            "$mat.roughnessFactor",
+            "$mat.gltf.pbrMetallicRoughness.roughnessFactor",
+        ],
+        TextureType::None,
+        [1.0],
+    );
+    let [r, g, b] = get_factor(
+        assimp_material,
+        &["$clr.emissive"],
+        TextureType::None,
+        [0.0, 0.0, 0.0],
+    );
+    let [occlusion_strength] = get_factor(
+        assimp_material,
+        &["$tex.strength"],
+        TextureType::LightMap,
+        [1.0],
+    );
+    let [normal_scale] = get_factor(
+        assimp_material,
+        &["$tex.scale"],
+        TextureType::Normals,
+        [1.0],
+    );
+
+    let material_data = deferred_frag_mod::ty::MaterialData {
+        base_color_factor: get_factor(
+            assimp_material,
+            &[
+                "$clr.base",
+                "$mat.gltf.pbrMetallicRoughness.baseColorFactor",
+                "$clr.diffuse",
+            ],
+            TextureType::None,
+            [1.0, 1.0, 1.0, 1.0],
+        ),
+        emissive_factor: [r, g, b],
+        metallic_factor,
+        roughness_factor,
+        occlusion_strength,
+        normal_scale,
+        base_color_texture: 0,
+        metallic_roughness_texture: 0,
+        occlusion_texture: 0,
+        emissive_texture: 0,
+        normal_texture: 0,
     };
 
     Material {
-        uniform_set: Some(uniform_set),
+        textures: [base_color, metallic_roughness, occlusion, emissive, normal],
+        data: material_data,
+    }
+}
+
+/// Loads all materials, spread over multiple threads as decoding the textures is slow.
+fn load_materials(
+    queue: &Arc<Queue>,
+    assimp_materials: &[russimp::material::Material],
+    base_dir: &str,
+    errors: &mut Vec<AssetError>,
+    progress: &LoadProgress,
+    cache: &TextureCache,
+) -> Vec<Material> {
+    let thread_count = thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = assimp_materials.len().div_ceil(thread_count).max(1);
+
+    let chunks: Vec<Vec<(Material, Vec<AssetError>)>> = thread::scope(|scope| {
+        let workers: Vec<_> = assimp_materials
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|assimp_material| {
+                            let mut errors = vec![];
+                            let material =
+                                load_material(queue, assimp_material, base_dir, &mut errors, cache);
+                            progress.finish_work(1);
+                            (material, errors)
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .collect()
+    });
+
+    chunks
+        .into_iter()
+        .flatten()
+        .map(|(material, material_errors)| {
+            errors.extend(material_errors);
+            material
+        })
+        .collect()
+}
+
+fn to_vector(v: &Vector3D) -> Vector3<f32> {
+    Vector3::new(v.x, v.y, v.z)
+}
+
+fn to_matrix(m: &Matrix4x4) -> Matrix4<f32> {
+    // Assimp matrices are row-major, cgmath expects the columns.
+    Matrix4::from([
+        [m.a1, m.b1, m.c1, m.d1],
+        [m.a2, m.b2, m.c2, m.d2],
+        [m.a3, m.b3, m.c3, m.d3],
+        [m.a4, m.b4, m.c4, m.d4],
+    ])
+}
+
+fn load_node(scene: &mut Scene, assimp_node: &Node, parent: NodeId) {
+    let id = scene.add_node(
+        Some(parent),
+        &assimp_node.name,
+        to_matrix(&assimp_node.transformation),
+    );
+    scene.nodes[id].mesh_buffers = assimp_node.meshes.iter().map(|&m| m as usize).collect();
+
+    for child in assimp_node.children.borrow().iter() {
+        load_node(scene, child, id);
     }
 }
 
-pub fn load_gltf(system: &System, layout: &Arc<DescriptorSetLayout>, file_path: &Path) -> Mesh {
-    let base_dir = file_path.parent().unwrap().to_str().unwrap().to_owned() + "/";
-    let scene = Scene::from_file(
-        file_path.to_str().unwrap(),
+/// Loads a model as a scene.
+///
+/// Problems that only affect part of the model, like a texture that can't be decoded, are added to
+/// `errors` and the model is loaded with a fallback in its place. Every loaded material and mesh
+/// is counted in `progress`, and textures are shared with other models through `cache`.
+///
+/// The textures and geometry are uploaded with `queue` before the model is returned, see
+/// `Scene::upload_geometry`.
+pub fn load_gltf(
+    queue: &Arc<Queue>,
+    file_path: &Path,
+    errors: &mut Vec<AssetError>,
+    progress: &LoadProgress,
+    cache: &TextureCache,
+) -> Result<Scene, AssetError> {
+    // Assimp's error for a missing file is rather vague.
+    fs::metadata(file_path).map_err(|error| AssetError::Io {
+        path: file_path.to_owned(),
+        error,
+    })?;
+
+    let base_dir = file_path.parent().unwrap().to_string_lossy().into_owned() + "/";
+    let scene = russimp::scene::Scene::from_file(
+        &file_path.to_string_lossy(),
         vec![
+            // Only generates normals for meshes that have none, every vertex needs one.
+            PostProcess::GenerateNormals,
             PostProcess::CalculateTangentSpace,
             PostProcess::Triangulate,
             PostProcess::JoinIdenticalVertices,
             PostProcess::SortByPrimitiveType,
         ],
     )
-    .expect("Could not load model");
+    .map_err(|error| AssetError::Import {
+        path: file_path.to_owned(),
+        error,
+    })?;
+
+    progress.add_work(scene.materials.len() + scene.meshes.len());
+    let materials = load_materials(queue, &scene.materials, &base_dir, errors, progress, cache);
 
-    let materials: Vec<Material> = vec![];
-    let mut mesh_buffers: Vec<MeshBuffer> = vec![];
+    let mut result = Scene::new();
+    result.materials = materials;
 
     for mesh in &scene.meshes {
-        let assimp_vertices = &mesh.vertices;
-        let assimp_normals = &mesh.normals;
-        let assimp_faces = &mesh.faces;
         let assimp_tex_coords = &mesh.texture_coords;
-        let material = load_material(
-            system,
-            layout,
-            scene.materials.get(mesh.material_index as usize).unwrap(),
-            &base_dir,
-        );
-
-        let vertices: Vec<Vertex> = assimp_vertices
-            .iter()
-            .map(|v| Vertex {
-                position: [v.x, v.y, v.z],
-            })
-            .collect();
-        let normals: Vec<Normal> = assimp_normals
-            .iter()
-            .map(|v| Normal {
-                normal: [v.x, v.y, v.z],
-            })
-            .collect();
-        let indices: Vec<u32> = assimp_faces
+
+        let indices: Vec<u32> = mesh
+            .faces
             .iter()
             .map(|f| [f.0[0], f.0[1], f.0[2]])
             .flatten()
             .collect();
 
-        if assimp_tex_coords[0].is_none() {
-            panic!("Could not find texture coordinates");
-        }
+        // Meshes without texture coordinates are still drawn using the factors of their material.
+        let tex_coords: Vec<[f32; 2]> = match assimp_tex_coords.first().and_then(Option::as_ref) {
+            Some(assimp_tex_coords) => assimp_tex_coords
+                .iter()
+                .map(|tc| [tc.x, 1.0 - tc.y])
+                .collect(),
+            None => vec![[0.0; 2]; mesh.vertices.len()],
+        };
 
-        let tex_coords: Vec<TexCoord> = assimp_tex_coords[0]
-            .as_ref()
-            .unwrap()
-            .into_iter()
-            .map(|tc| TexCoord {
-                tex_coord: [tc.x, 1.0 - tc.y],
+        // Assimp leaves the tangents empty if it could not compute them, in which case the
+        // zero tangents disable normal mapping for this mesh.
+        let tangents: Vec<[f32; 4]> = if mesh.tangents.is_empty() {
+            vec![[0.0; 4]; mesh.vertices.len()]
+        } else {
+            mesh.tangents
+                .iter()
+                .zip(&mesh.bitangents)
+                .zip(&mesh.normals)
+                .map(|((t, b), n)| {
+                    let (t, b, n) = (to_vector(t), to_vector(b), to_vector(n));
+                    let handedness = if n.cross(t).dot(b) < 0.0 { -1.0 } else { 1.0 };
+                    [t.x, t.y, t.z, handedness]
+                })
+                .collect()
+        };
+
+        let vertices: Vec<Vertex> = mesh
+            .vertices
+            .iter()
+            .zip(&mesh.normals)
+            .zip(tex_coords)
+            .zip(tangents)
+            .map(|(((v, n), tex_coord), tangent)| Vertex {
+                position: [v.x, v.y, v.z],
+                normal: [n.x, n.y, n.z],
+                tex_coord,
+                tangent,
             })
             .collect();
 
-        let vertex_buffer = CpuAccessibleBuffer::from_iter(
-            system.device.clone(),
-            BufferUsage::all(),
-            false,
-            vertices,
-        )
-        .unwrap();
-        let normal_buffer = CpuAccessibleBuffer::from_iter(
-            system.device.clone(),
-            BufferUsage::all(),
-            false,
-            normals,
-        )
-        .unwrap();
-        let index_buffer = CpuAccessibleBuffer::from_iter(
-            system.device.clone(),
-            BufferUsage::all(),
-            false,
-            indices,
-        )
-        .unwrap();
-        let tex_coord_buffer = CpuAccessibleBuffer::from_iter(
-            system.device.clone(),
-            BufferUsage::all(),
-            false,
-            tex_coords,
-        )
-        .unwrap();
-
-        mesh_buffers.push(MeshBuffer {
-            vertex_buffer,
-            normal_buffer,
-            index_buffer,
-            tex_coord_buffer,
-            material,
+        let positions: Vec<Point3<f32>> = mesh
+            .vertices
+            .iter()
+            .map(|v| Point3::new(v.x, v.y, v.z))
+            .collect();
+        let aabb = Aabb::from_points(positions.iter().copied());
+        let sphere = BoundingSphere::from_points(&positions, &aabb);
+
+        result.mesh_buffers.push(MeshBuffer {
+            first_index: result.indices.len() as u32,
+            index_count: indices.len() as u32,
+            vertex_offset: result.vertices.len() as i32,
+            material: mesh.material_index as usize,
+            aabb,
+            sphere,
         });
+        result.vertices.extend(vertices);
+        result.indices.extend(indices);
+        progress.finish_work(1);
     }
 
-    Mesh {
-        mesh_buffers,
-        materials,
-        model_matrix: Matrix4::from_scale(1.0),
+    if let Some(root) = &scene.root {
+        let parent = result.root;
+        load_node(&mut result, root, parent);
     }
-}
+    result.upload_geometry(queue);
 
-impl Mesh {
-    pub fn render(
-        &self,
-        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
-        pipeline: &Arc<GraphicsPipeline>,
-        general_set: &Arc<PersistentDescriptorSet>,
-    ) {
-        for mesh_buffer in &self.mesh_buffers {
-            let vertex_buffers = (
-                mesh_buffer.vertex_buffer.clone(),
-                mesh_buffer.normal_buffer.clone(),
-                mesh_buffer.tex_coord_buffer.clone(),
-            );
-
-            let uniform_set = mesh_buffer.material.uniform_set.as_ref().unwrap();
-
-            builder
-                .bind_descriptor_sets(
-                    PipelineBindPoint::Graphics,
-                    pipeline.layout().clone(),
-                    0,
-                    vec![general_set.clone(), uniform_set.clone()],
-                )
-                .bind_vertex_buffers(0, vertex_buffers)
-                .bind_index_buffer(mesh_buffer.index_buffer.clone())
-                .draw_indexed(mesh_buffer.index_buffer.len() as u32, 1, 0, 0, 0)
-                .unwrap();
-        }
-    }
+    Ok(result)
 }
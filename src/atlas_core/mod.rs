@@ -1,17 +1,23 @@
+use std::collections::VecDeque;
 use std::sync::Arc;
 use std::time::Instant;
 use vulkano::device::Features;
 use vulkano::{
+    command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, SubpassContents},
     device::{
         physical::{PhysicalDevice, PhysicalDeviceType},
         Device, DeviceCreateInfo, DeviceExtensions, Queue, QueueCreateInfo,
     },
     format::Format,
-    image::{view::ImageView, AttachmentImage, ImageAccess, ImageUsage, SwapchainImage},
+    image::{
+        view::{ImageView, ImageViewAbstract},
+        AttachmentImage, ImageAccess, ImageUsage, SwapchainImage,
+    },
     instance::{Instance, InstanceCreateInfo},
     pipeline::graphics::viewport::Viewport,
     render_pass::{Framebuffer, FramebufferCreateInfo, RenderPass},
     swapchain::{Surface, Swapchain, SwapchainCreateInfo},
+    sync::{self, GpuFuture},
 };
 
 use vulkano_win::VkSurfaceBuild;
@@ -21,19 +27,47 @@ use winit::{
     window::{Window, WindowBuilder},
 };
 
-use self::renderer::deferred::DeferredRenderPass;
+use self::camera::Camera;
+use self::capture::{record_capture, Capture};
+use self::renderer::deferred::{DeferredRenderPass, DeferredRenderer};
+use self::scene::{CullingStats, Scene};
+use self::timing::{FrameTiming, GpuPass, PassTimings};
 
+pub mod bookmarks;
+pub mod bounds;
 pub mod camera;
+pub mod capture;
+pub mod decoder;
 pub mod egui;
+pub mod error;
+pub mod loader;
 pub mod mesh;
 pub mod renderer;
+pub mod scene;
 pub mod texture;
+pub mod timing;
 
 use renderer::deferred;
 
+/// Frames of GPU timings that are kept for the graph in the UI.
+pub const GPU_TIMING_HISTORY: usize = 240;
+
 pub struct PerformanceInfo {
     pub game_start: Instant,
     pub delta_time_ms: f32,
+    pub culling: CullingStats,
+    /// GPU timings of the last `GPU_TIMING_HISTORY` frames, oldest first. Lags a few frames
+    /// behind, as they are read once the GPU has finished the frame.
+    pub gpu_timings: VecDeque<PassTimings>,
+}
+
+impl PerformanceInfo {
+    pub fn push_gpu_timings(&mut self, timings: PassTimings) {
+        if self.gpu_timings.len() == GPU_TIMING_HISTORY {
+            self.gpu_timings.pop_front();
+        }
+        self.gpu_timings.push_back(timings);
+    }
 }
 
 pub struct SystemInfo {
@@ -49,34 +83,70 @@ pub struct System {
     pub images: Vec<Arc<SwapchainImage<Window>>>,
     pub surface: Arc<Surface<Window>>,
     pub queue: Arc<Queue>,
+    /// Queue for uploading assets in the background, which may be the same as `queue`.
+    pub transfer_queue: Arc<Queue>,
     pub render_pass: DeferredRenderPass,
 }
 
-pub fn init(title: &str) -> System {
-    let required_extensions = vulkano_win::required_extensions();
-    let instance = Instance::new(InstanceCreateInfo {
-        enabled_extensions: required_extensions,
-        ..Default::default()
-    })
-    .unwrap();
+/// Renders into an offscreen image instead of a window, for machines without a display.
+pub struct HeadlessSystem {
+    pub info: SystemInfo,
+    pub device: Arc<Device>,
+    pub queue: Arc<Queue>,
+    pub render_pass: DeferredRenderPass,
+    pub image: Arc<AttachmentImage>,
+    pub gbuffer: GBuffer,
+    pub viewport: Viewport,
+}
 
-    let event_loop = EventLoop::new();
-    let surface = WindowBuilder::new()
-        .with_title(title)
-        .with_inner_size(LogicalSize::new(3000.0, 2000.0))
-        // .with_fullscreen(Some(Fullscreen::Borderless(None)))
-        .build_vk_surface(&event_loop, instance.clone())
-        .expect("Failed to create a window");
+pub struct GBuffer {
+    pub framebuffers: Vec<Arc<Framebuffer>>,
+    pub color_buffer: Arc<ImageView<AttachmentImage>>,
+    pub normal_buffer: Arc<ImageView<AttachmentImage>>,
+    pub position_buffer: Arc<ImageView<AttachmentImage>>,
+    pub emissive_buffer: Arc<ImageView<AttachmentImage>>,
+}
 
-    let device_extensions = DeviceExtensions {
-        khr_swapchain: true,
-        ..DeviceExtensions::none()
-    };
-    let (physical_device, queue_family) = PhysicalDevice::enumerate(&instance)
+/// Features the renderer can't work without: a bindless array of the scene's textures, and a
+/// single indirect draw for all meshes, which are told apart by their first instance.
+fn required_features() -> Features {
+    Features {
+        descriptor_indexing: true,
+        shader_uniform_buffer_array_non_uniform_indexing: true,
+        shader_sampled_image_array_non_uniform_indexing: true,
+        runtime_descriptor_array: true,
+        descriptor_binding_variable_descriptor_count: true,
+        multi_draw_indirect: true,
+        draw_indirect_first_instance: true,
+        ..Features::none()
+    }
+}
+
+fn create_device(
+    instance: &Arc<Instance>,
+    device_extensions: DeviceExtensions,
+    surface: Option<&Arc<Surface<Window>>>,
+) -> (SystemInfo, Arc<Device>, Arc<Queue>, Arc<Queue>) {
+    let required_features = required_features();
+    let (physical_device, queue_family) = PhysicalDevice::enumerate(instance)
         .filter(|&p| p.supported_extensions().is_superset_of(&device_extensions))
+        .filter(|&p| {
+            let missing = required_features.difference(p.supported_features());
+            if missing != Features::none() {
+                eprintln!(
+                    "Skipping {}, which doesn't support {:?}",
+                    p.properties().device_name,
+                    missing
+                );
+            }
+            missing == Features::none()
+        })
         .filter_map(|p| {
             p.queue_families()
-                .find(|&q| q.supports_graphics() && q.supports_surface(&surface).unwrap_or(false))
+                .find(|&q| {
+                    q.supports_graphics()
+                        && surface.map_or(true, |s| q.supports_surface(s).unwrap_or(false))
+                })
                 .map(|q| (p, q))
         })
         .min_by_key(|(p, _)| match p.properties().device_type {
@@ -86,10 +156,26 @@ pub fn init(title: &str) -> System {
             PhysicalDeviceType::Cpu => 3,
             PhysicalDeviceType::Other => 4,
         })
-        .unwrap();
+        .expect("No Vulkan device supports the extensions and features the renderer requires");
 
     let systtem_properties = physical_device.properties();
 
+    // Prefer a separate queue for transfers, so uploading assets doesn't stall rendering.
+    let transfer_family = physical_device
+        .queue_families()
+        .find(|&q| q.explicitly_supports_transfers() && !q.supports_graphics());
+    let queue_create_infos = match transfer_family {
+        Some(transfer_family) => vec![
+            QueueCreateInfo::family(queue_family),
+            QueueCreateInfo::family(transfer_family),
+        ],
+        None if queue_family.queues_count() > 1 => vec![QueueCreateInfo {
+            queues: vec![0.5; 2],
+            ..QueueCreateInfo::family(queue_family)
+        }],
+        None => vec![QueueCreateInfo::family(queue_family)],
+    };
+
     let (device, mut queues) = Device::new(
         physical_device,
         DeviceCreateInfo {
@@ -97,21 +183,52 @@ pub fn init(title: &str) -> System {
                 .required_extensions()
                 .union(&device_extensions),
             enabled_features: Features {
-                descriptor_indexing: true,
-                shader_uniform_buffer_array_non_uniform_indexing: true,
-                runtime_descriptor_array: true,
-                descriptor_binding_variable_descriptor_count: true,
-                ..Features::none()
+                sampler_anisotropy: physical_device.supported_features().sampler_anisotropy,
+                texture_compression_bc: physical_device.supported_features().texture_compression_bc,
+                ..required_features
             },
-            queue_create_infos: vec![QueueCreateInfo::family(queue_family)],
+            queue_create_infos,
             ..Default::default()
         },
     )
     .unwrap();
 
     let queue = queues.next().unwrap();
+    let transfer_queue = queues.next().unwrap_or_else(|| queue.clone());
+
+    let info = SystemInfo {
+        device_name: systtem_properties.device_name.clone(),
+        device_type: format!("{:?}", systtem_properties.device_type),
+    };
+
+    (info, device, queue, transfer_queue)
+}
+
+pub fn init(title: &str) -> System {
+    let required_extensions = vulkano_win::required_extensions();
+    let instance = Instance::new(InstanceCreateInfo {
+        enabled_extensions: required_extensions,
+        ..Default::default()
+    })
+    .unwrap();
+
+    let event_loop = EventLoop::new();
+    let surface = WindowBuilder::new()
+        .with_title(title)
+        .with_inner_size(LogicalSize::new(3000.0, 2000.0))
+        // .with_fullscreen(Some(Fullscreen::Borderless(None)))
+        .build_vk_surface(&event_loop, instance.clone())
+        .expect("Failed to create a window");
+
+    let device_extensions = DeviceExtensions {
+        khr_swapchain: true,
+        ..DeviceExtensions::none()
+    };
+    let (info, device, queue, transfer_queue) =
+        create_device(&instance, device_extensions, Some(&surface));
 
     let (swapchain, images) = {
+        let physical_device = device.physical_device();
         let surface_capabilities = physical_device
             .surface_capabilities(&surface, Default::default())
             .unwrap();
@@ -123,7 +240,12 @@ pub fn init(title: &str) -> System {
                 min_image_count: surface_capabilities.min_image_count,
                 image_format: Some(Format::B8G8R8A8_SRGB),
                 image_extent: surface.window().inner_size().into(),
-                image_usage: ImageUsage::color_attachment(),
+                // Allow copying the presented image back for screenshots.
+                image_usage: ImageUsage {
+                    color_attachment: true,
+                    transfer_source: true,
+                    ..ImageUsage::none()
+                },
                 composite_alpha: surface_capabilities
                     .supported_composite_alpha
                     .iter()
@@ -135,20 +257,145 @@ pub fn init(title: &str) -> System {
         .unwrap()
     };
 
-    let render_pass = deferred::init_render_pass(&device, &swapchain);
+    let render_pass = deferred::init_render_pass(&device, swapchain.image_format());
 
     System {
-        info: SystemInfo {
-            device_name: systtem_properties.device_name.clone(),
-            device_type: format!("{:?}", systtem_properties.device_type),
-        },
+        info,
         event_loop,
         device,
         swapchain,
         images,
         surface,
         queue,
+        transfer_queue,
+        render_pass,
+    }
+}
+
+pub fn init_headless(dimensions: [u32; 2]) -> HeadlessSystem {
+    let instance = Instance::new(InstanceCreateInfo::default()).unwrap();
+
+    let (info, device, queue, _) = create_device(&instance, DeviceExtensions::none(), None);
+
+    let image = AttachmentImage::with_usage(
+        device.clone(),
+        dimensions,
+        Format::R8G8B8A8_SRGB,
+        ImageUsage {
+            color_attachment: true,
+            transfer_source: true,
+            ..ImageUsage::none()
+        },
+    )
+    .unwrap();
+
+    let render_pass = deferred::init_render_pass(&device, image.format());
+
+    let mut viewport = Viewport {
+        origin: [0.0, 0.0],
+        dimensions: [0.0, 0.0],
+        depth_range: 0.0..1.0,
+    };
+    let gbuffer = offscreen_setup(
+        device.clone(),
+        &image,
+        render_pass.render_pass.clone(),
+        &mut viewport,
+    );
+
+    HeadlessSystem {
+        info,
+        device,
+        queue,
         render_pass,
+        image,
+        gbuffer,
+        viewport,
+    }
+}
+
+impl HeadlessSystem {
+    /// Renders a single frame into `image` and blocks until the GPU is done.
+    pub fn render_frame(
+        &self,
+        renderer: &mut DeferredRenderer,
+        camera: &mut Camera,
+        scene: &mut Scene,
+    ) -> FrameTiming {
+        let frame_start = Instant::now();
+
+        camera.aspect_ratio = self.viewport.dimensions[0] / self.viewport.dimensions[1];
+        camera.update();
+        scene.update_transforms();
+
+        let mut builder = AutoCommandBufferBuilder::primary(
+            self.device.clone(),
+            self.queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+
+        renderer.draw(
+            &mut builder,
+            self.gbuffer.framebuffers[0].clone(),
+            &self.gbuffer,
+            &self.viewport,
+            camera,
+            scene,
+            &self.render_pass.params,
+        );
+
+        // There is no UI offscreen, so skip over the egui subpass.
+        builder
+            .next_subpass(SubpassContents::Inline)
+            .unwrap()
+            .end_render_pass()
+            .unwrap();
+        // Times the skipped UI subpass as well, so the timestamps are complete.
+        renderer.end_pass(&mut builder, GpuPass::Ui);
+
+        let command_buffer = builder.build().unwrap();
+
+        let future = sync::now(self.device.clone())
+            .then_execute(self.queue.clone(), command_buffer)
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap();
+        let cpu_ms = frame_start.elapsed().as_secs_f32() * 1000.0;
+        future.wait(None).unwrap();
+
+        FrameTiming {
+            cpu_ms,
+            gpu_ms: renderer
+                .gpu_timer
+                .as_ref()
+                .and_then(|timer| timer.read_current())
+                .map(|timings| timings.total()),
+            frame_ms: frame_start.elapsed().as_secs_f32() * 1000.0,
+        }
+    }
+
+    /// Copies the last rendered frame back to the CPU.
+    pub fn capture(&self) -> Capture {
+        let mut builder = AutoCommandBufferBuilder::primary(
+            self.device.clone(),
+            self.queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+
+        let capture = record_capture(&self.device, &mut builder, self.image.clone());
+        let command_buffer = builder.build().unwrap();
+
+        sync::now(self.device.clone())
+            .then_execute(self.queue.clone(), command_buffer)
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+
+        capture
     }
 }
 
@@ -157,17 +404,38 @@ pub fn window_size_dependent_setup(
     images: &[Arc<SwapchainImage<Window>>],
     render_pass: Arc<RenderPass>,
     viewport: &mut Viewport,
-) -> (
-    Vec<Arc<Framebuffer>>,
-    Arc<ImageView<AttachmentImage>>,
-    Arc<ImageView<AttachmentImage>>,
-    Arc<ImageView<AttachmentImage>>,
-) {
+) -> GBuffer {
     let dimensions = images[0].dimensions().width_height();
-    viewport.dimensions = [dimensions[0] as f32, dimensions[1] as f32];
+    let views = images
+        .iter()
+        .map(|image| ImageView::new_default(image.clone()).unwrap() as Arc<dyn ImageViewAbstract>)
+        .collect();
+
+    gbuffer_setup(device, views, dimensions, render_pass, viewport)
+}
+
+pub fn offscreen_setup(
+    device: Arc<Device>,
+    image: &Arc<AttachmentImage>,
+    render_pass: Arc<RenderPass>,
+    viewport: &mut Viewport,
+) -> GBuffer {
+    let dimensions = image.dimensions().width_height();
+    let views = vec![ImageView::new_default(image.clone()).unwrap() as Arc<dyn ImageViewAbstract>];
 
+    gbuffer_setup(device, views, dimensions, render_pass, viewport)
+}
+
+fn gbuffer_setup(
+    device: Arc<Device>,
+    final_views: Vec<Arc<dyn ImageViewAbstract>>,
+    dimensions: [u32; 2],
+    render_pass: Arc<RenderPass>,
+    viewport: &mut Viewport,
+) -> GBuffer {
+    viewport.dimensions = [dimensions[0] as f32, dimensions[1] as f32];
     let depth_buffer = ImageView::new_default(
-        AttachmentImage::transient(device.clone(), dimensions, Format::D16_UNORM).unwrap(),
+        AttachmentImage::transient(device.clone(), dimensions, Format::D32_SFLOAT).unwrap(),
     )
     .unwrap();
     let color_buffer = ImageView::new_default(
@@ -197,11 +465,19 @@ pub fn window_size_dependent_setup(
         .unwrap(),
     )
     .unwrap();
+    let emissive_buffer = ImageView::new_default(
+        AttachmentImage::transient_input_attachment(
+            device.clone(),
+            dimensions,
+            Format::R16G16B16A16_SFLOAT,
+        )
+        .unwrap(),
+    )
+    .unwrap();
 
-    let framebuffers = images
-        .iter()
-        .map(|image| {
-            let view = ImageView::new_default(image.clone()).unwrap();
+    let framebuffers = final_views
+        .into_iter()
+        .map(|view| {
             Framebuffer::new(
                 render_pass.clone(),
                 FramebufferCreateInfo {
@@ -210,6 +486,7 @@ pub fn window_size_dependent_setup(
                         color_buffer.clone(),
                         normal_buffer.clone(),
                         position_buffer.clone(),
+                        emissive_buffer.clone(),
                         depth_buffer.clone(),
                     ],
                     ..Default::default()
@@ -219,5 +496,11 @@ pub fn window_size_dependent_setup(
         })
         .collect::<Vec<_>>();
 
-    (framebuffers, color_buffer, normal_buffer, position_buffer)
+    GBuffer {
+        framebuffers,
+        color_buffer,
+        normal_buffer,
+        position_buffer,
+        emissive_buffer,
+    }
 }
@@ -3,24 +3,35 @@ use std::{f32::consts::PI, sync::Arc};
 use cgmath::Vector4;
 use vulkano::{
     buffer::{cpu_pool::CpuBufferPoolSubbuffer, BufferUsage, CpuBufferPool},
-    device::Device,
+    command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer, SubpassContents},
+    descriptor_set::{PersistentDescriptorSet, WriteDescriptorSet},
+    device::{Device, DeviceOwned, Queue},
     format::Format,
     memory::pool::StdMemoryPool,
     pipeline::{
         graphics::{
-            color_blend::ColorBlendState, depth_stencil::DepthStencilState,
-            input_assembly::InputAssemblyState, vertex_input::BuffersDefinition,
-            viewport::ViewportState,
+            color_blend::ColorBlendState,
+            depth_stencil::{CompareOp, DepthStencilState},
+            input_assembly::InputAssemblyState,
+            vertex_input::BuffersDefinition,
+            viewport::{Viewport, ViewportState},
         },
-        GraphicsPipeline,
+        GraphicsPipeline, Pipeline, PipelineBindPoint, StateMode,
     },
-    render_pass::{RenderPass, Subpass},
-    swapchain::Swapchain,
+    render_pass::{Framebuffer, RenderPass, Subpass},
 };
 
-use winit::window::Window;
+use crate::atlas_core::bounds::Frustum;
+use crate::atlas_core::camera::Camera;
+use crate::atlas_core::mesh::{Vertex, Vertex2D};
+use crate::atlas_core::scene::{CullingStats, DrawListPools, Scene};
+use crate::atlas_core::texture::SamplerSettings;
+use crate::atlas_core::timing::{GpuPass, GpuTimer};
+use crate::atlas_core::GBuffer;
 
-use crate::atlas_core::mesh::{Normal, TexCoord, Vertex, Vertex2D};
+use super::light::{get_light_storage_buffer, Light, LightData};
+use super::shadow::{compute_cascades, Cascades, ShadowMap};
+use super::triangle_draw_system::TriangleDrawSystem;
 
 use self::lighting_frag_mod::ty::LightingData;
 
@@ -47,9 +58,22 @@ pub struct RendererParams {
     pub ambient_color: [f32; 4],
     pub directional_direction: [f32; 4],
     pub directional_color: [f32; 4],
+    pub lights: Vec<Light>,
+    /// Width and height of each shadow cascade in texels.
+    pub shadow_resolution: u32,
+    /// Depth offset to prevent surfaces from shadowing themselves.
+    pub shadow_bias: f32,
+    pub cascade_count: u32,
+    /// Distance from the camera up to which shadows are rendered.
+    pub shadow_distance: f32,
     pub preview_buffer: DebugPreviewBuffer,
+    /// Filtering of the material textures.
+    pub sampler: SamplerSettings,
 }
 
+/// Maximum number of textures in the bindless texture array of a scene.
+pub const MAX_TEXTURES: u32 = 4096;
+
 pub struct DeferredRenderPass {
     pub render_pass: Arc<RenderPass>,
     pub deferred_pass: Subpass,
@@ -80,13 +104,21 @@ pub fn get_default_params() -> RendererParams {
             w: 0.0,
         }
         .into(),
+        lights: vec![],
+        shadow_resolution: 2048,
+        shadow_bias: 0.002,
+        cascade_count: 3,
+        shadow_distance: 3000.0,
         preview_buffer: DebugPreviewBuffer::FinalOutput,
+        sampler: SamplerSettings::default(),
     }
 }
 
 pub fn get_lighting_uniform_buffer(
     device: &Arc<Device>,
     params: &RendererParams,
+    camera: &Camera,
+    cascades: &Cascades,
 ) -> Arc<CpuBufferPoolSubbuffer<LightingData, Arc<StdMemoryPool>>> {
     let lighting_buffer = CpuBufferPool::<lighting_frag_mod::ty::LightingData>::new(
         device.clone(),
@@ -97,23 +129,27 @@ pub fn get_lighting_uniform_buffer(
         ambient_color: params.ambient_color,
         directional_direction: params.directional_direction,
         directional_color: params.directional_color,
+        camera_position: camera.position.to_homogeneous().into(),
+        view: camera.view.into(),
+        cascade_matrices: cascades.matrices.map(|m| m.into()),
+        cascade_splits: cascades.splits,
         preview_type: params.preview_buffer as i32,
+        light_count: params.lights.len() as i32,
+        cascade_count: params.cascade_count as i32,
+        shadow_bias: params.shadow_bias,
     };
 
     lighting_buffer.next(uniform_data).unwrap()
 }
 
-pub fn init_render_pass(
-    device: &Arc<Device>,
-    swapchain: &Arc<Swapchain<Window>>,
-) -> DeferredRenderPass {
+pub fn init_render_pass(device: &Arc<Device>, final_format: Format) -> DeferredRenderPass {
     let render_pass = vulkano::ordered_passes_renderpass!(
         device.clone(),
         attachments: {
             final_color: {
                 load: Clear,
                 store: Store,
-                format: swapchain.image_format(),
+                format: final_format,
                 samples: 1,
             },
             albedo: {
@@ -134,25 +170,33 @@ pub fn init_render_pass(
                 format: Format::R16G16B16A16_SFLOAT,
                 samples: 1,
             },
+            emissive: {
+                load: Clear,
+                store: DontCare,
+                format: Format::R16G16B16A16_SFLOAT,
+                samples: 1,
+            },
             depth: {
                 load: Clear,
                 store: DontCare,
-                format: Format::D16_UNORM,
+                format: Format::D32_SFLOAT,
                 samples: 1,
             }
         },
         passes: [
-            // Deferred pass. Write to the diffuse, normals and depth attachments.
+            // Deferred pass. Write to the diffuse, normals, positions, emissive and depth
+            // attachments. The alpha channels of the normals, positions and emissive attachments
+            // hold the metallic, roughness and occlusion of the material respectively.
             {
-                color: [albedo, normals, positions],
+                color: [albedo, normals, positions, emissive],
                 depth_stencil: {depth},
                 input: []
             },
-            // Apply lighting by reading these three attachments and writing to `final_color`.
+            // Apply lighting by reading these four attachments and writing to `final_color`.
             {
                 color: [final_color],
                 depth_stencil: {},
-                input: [albedo, normals, positions] //, depth
+                input: [albedo, normals, positions, emissive] //, depth
             },
             // egui renderpass
             { color: [final_color], depth_stencil: {}, input: [] }
@@ -171,35 +215,49 @@ pub fn init_render_pass(
     }
 }
 
+/// With `reverse_z`, depth is 1 at the near plane and 0 at the far plane, so closer fragments
+/// have a greater depth.
 pub fn init_pipelines(
     device: &Arc<Device>,
-    render_pass: &DeferredRenderPass,
+    render_pass: &Arc<RenderPass>,
+    reverse_z: bool,
 ) -> (Arc<GraphicsPipeline>, Arc<GraphicsPipeline>) {
     let deferred_vert = deferred_vert_mod::load(device.clone()).unwrap();
     let deferred_frag = deferred_frag_mod::load(device.clone()).unwrap();
     let lighting_vert = lighting_vert_mod::load(device.clone()).unwrap();
     let lighting_frag = lighting_frag_mod::load(device.clone()).unwrap();
 
-    let deferred_pass = Subpass::from(render_pass.render_pass.clone(), 0).unwrap();
-    let lighting_pass = Subpass::from(render_pass.render_pass.clone(), 1).unwrap();
+    let deferred_pass = Subpass::from(render_pass.clone(), 0).unwrap();
+    let lighting_pass = Subpass::from(render_pass.clone(), 1).unwrap();
+
+    // Bounded by the limit on the number of textures per shader stage.
+    let properties = device.physical_device().properties();
+    let max_textures = MAX_TEXTURES
+        .min(properties.max_per_stage_descriptor_samplers)
+        .min(properties.max_per_stage_descriptor_sampled_images);
 
-    let vertex_input_state = BuffersDefinition::new()
-        .vertex::<Vertex>()
-        .vertex::<Normal>()
-        .vertex::<TexCoord>();
+    let mut depth_stencil_state = DepthStencilState::simple_depth_test();
+    if reverse_z {
+        depth_stencil_state.depth.as_mut().unwrap().compare_op =
+            StateMode::Fixed(CompareOp::Greater);
+    }
 
     let deferred_pipeline = GraphicsPipeline::start()
-        .vertex_input_state(vertex_input_state)
+        .vertex_input_state(BuffersDefinition::new().vertex::<Vertex>())
         .vertex_shader(deferred_vert.entry_point("main").unwrap(), ())
         .input_assembly_state(InputAssemblyState::new())
         .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
         .fragment_shader(deferred_frag.entry_point("main").unwrap(), ())
-        .color_blend_state(
-            ColorBlendState::new(deferred_pass.num_color_attachments()).blend_alpha(),
-        )
-        .depth_stencil_state(DepthStencilState::simple_depth_test())
+        // No blending, as the alpha channels of the G-buffer store material properties.
+        .color_blend_state(ColorBlendState::new(deferred_pass.num_color_attachments()))
+        .depth_stencil_state(depth_stencil_state)
         .render_pass(deferred_pass)
-        .build(device.clone())
+        .with_auto_layout(device.clone(), |set_layouts| {
+            // The texture array of the material set is sized by the scene, see `Scene::upload`.
+            let textures = set_layouts[1].bindings.get_mut(&1).unwrap();
+            textures.variable_descriptor_count = true;
+            textures.descriptor_count = max_textures;
+        })
         .unwrap();
 
     let lighting_pipeline = GraphicsPipeline::start()
@@ -215,6 +273,210 @@ pub fn init_pipelines(
     (deferred_pipeline, lighting_pipeline)
 }
 
+/// Everything needed to record the deferred and lighting subpasses of a frame.
+pub struct DeferredRenderer {
+    pub deferred_pipeline: Arc<GraphicsPipeline>,
+    pub lighting_pipeline: Arc<GraphicsPipeline>,
+    pub triangle_system: TriangleDrawSystem,
+    pub uniform_buffer: CpuBufferPool<deferred_vert_mod::ty::CameraData>,
+    pub shadow_map: ShadowMap,
+    pub draw_list_pools: DrawListPools,
+    pub light_buffer: CpuBufferPool<LightData>,
+    /// Culling results of the last frame.
+    pub culling: CullingStats,
+    /// Times the passes recorded by `draw`, `None` if the GPU doesn't support timestamps. The
+    /// caller ends the `GpuPass::Ui` pass.
+    pub gpu_timer: Option<GpuTimer>,
+    /// Whether the pipelines were built for a reverse-Z projection.
+    reverse_z: bool,
+    render_pass: Arc<RenderPass>,
+    queue: Arc<Queue>,
+}
+
+impl DeferredRenderer {
+    pub fn new(queue: &Arc<Queue>, render_pass: &DeferredRenderPass) -> DeferredRenderer {
+        let device = queue.device();
+        let (deferred_pipeline, lighting_pipeline) =
+            init_pipelines(device, &render_pass.render_pass, false);
+
+        DeferredRenderer {
+            deferred_pipeline,
+            lighting_pipeline,
+            triangle_system: TriangleDrawSystem::new(queue),
+            uniform_buffer: CpuBufferPool::new(device.clone(), BufferUsage::all()),
+            shadow_map: ShadowMap::new(
+                queue,
+                render_pass.params.shadow_resolution,
+                render_pass.params.cascade_count,
+            ),
+            draw_list_pools: DrawListPools::new(device),
+            light_buffer: CpuBufferPool::new(device.clone(), BufferUsage::storage_buffer()),
+            culling: CullingStats::default(),
+            gpu_timer: GpuTimer::new(queue),
+            reverse_z: false,
+            render_pass: render_pass.render_pass.clone(),
+            queue: queue.clone(),
+        }
+    }
+
+    /// Uploads the changes to the scene, records the shadow pass, begins the render pass and
+    /// records the deferred and lighting subpasses.
+    ///
+    /// The builder is left in the lighting subpass, so the caller has to advance to the egui
+    /// subpass and end the render pass.
+    pub fn draw(
+        &mut self,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        framebuffer: Arc<Framebuffer>,
+        gbuffer: &GBuffer,
+        viewport: &Viewport,
+        camera: &Camera,
+        scene: &mut Scene,
+        params: &RendererParams,
+    ) {
+        if self.shadow_map.resolution != params.shadow_resolution
+            || self.shadow_map.cascade_count != params.cascade_count
+        {
+            self.shadow_map =
+                ShadowMap::new(&self.queue, params.shadow_resolution, params.cascade_count);
+        }
+        if self.reverse_z != camera.reverse_z {
+            let (deferred_pipeline, lighting_pipeline) =
+                init_pipelines(self.queue.device(), &self.render_pass, camera.reverse_z);
+            self.deferred_pipeline = deferred_pipeline;
+            self.lighting_pipeline = lighting_pipeline;
+            self.reverse_z = camera.reverse_z;
+        }
+
+        // Models that finished loading since the last frame are added to the buffers of the
+        // scene before any pass uses them.
+        let material_layout = self
+            .deferred_pipeline
+            .layout()
+            .set_layouts()
+            .get(1)
+            .unwrap();
+        scene.upload(builder, material_layout, &params.sampler);
+
+        if let Some(timer) = &mut self.gpu_timer {
+            timer.begin_frame(builder);
+        }
+
+        let device = self.deferred_pipeline.device().clone();
+        let cascades = compute_cascades(camera, params, scene.get_bounds(scene.root));
+
+        let uniform_data = deferred_vert_mod::ty::CameraData {
+            view: camera.view.into(),
+            proj: camera.proj.into(),
+        };
+        let uniform_buffer_subbuffer = self.uniform_buffer.next(uniform_data).unwrap();
+
+        let deferred_layout = self
+            .deferred_pipeline
+            .layout()
+            .set_layouts()
+            .get(0)
+            .unwrap();
+        // Meshes outside of the view can still cast shadows into it, so only the deferred pass
+        // is culled.
+        let (shadow_draws, _) = scene.get_draw_list(&self.draw_list_pools, None);
+        let frustum = Frustum::from_matrix(&(camera.proj * camera.view));
+        let (draws, culling) = scene.get_draw_list(&self.draw_list_pools, Some(&frustum));
+        self.culling = culling;
+
+        let deferred_set = draws.as_ref().map(|draws| {
+            PersistentDescriptorSet::new(
+                deferred_layout.clone(),
+                [
+                    WriteDescriptorSet::buffer(0, uniform_buffer_subbuffer),
+                    WriteDescriptorSet::buffer(1, draws.transforms.clone()),
+                    WriteDescriptorSet::buffer(2, draws.materials.clone()),
+                ],
+            )
+            .unwrap()
+        });
+
+        let lighting_layout = self
+            .lighting_pipeline
+            .layout()
+            .set_layouts()
+            .get(0)
+            .unwrap();
+        let lighting_set = PersistentDescriptorSet::new(
+            lighting_layout.clone(),
+            [
+                WriteDescriptorSet::image_view(0, gbuffer.color_buffer.clone()),
+                WriteDescriptorSet::image_view(1, gbuffer.normal_buffer.clone()),
+                WriteDescriptorSet::image_view(2, gbuffer.position_buffer.clone()),
+                WriteDescriptorSet::buffer(
+                    3,
+                    get_lighting_uniform_buffer(&device, params, camera, &cascades),
+                ),
+                WriteDescriptorSet::buffer(
+                    4,
+                    get_light_storage_buffer(&self.light_buffer, &params.lights),
+                ),
+                WriteDescriptorSet::image_view_sampler(
+                    5,
+                    self.shadow_map.view.clone(),
+                    self.shadow_map.sampler.clone(),
+                ),
+                WriteDescriptorSet::image_view(6, gbuffer.emissive_buffer.clone()),
+            ],
+        )
+        .unwrap();
+
+        let clear_values = vec![
+            [0.0, 0.0, 0.0, 1.0].into(),
+            [0.0, 0.0, 0.0, 1.0].into(),
+            [0.0, 0.0, 0.0, 1.0].into(),
+            [0.0, 0.0, 0.0, 1.0].into(),
+            [0.0, 0.0, 0.0, 1.0].into(),
+            if camera.reverse_z { 0f32 } else { 1f32 }.into(),
+        ];
+
+        self.shadow_map
+            .draw(builder, &cascades, scene, shadow_draws.as_ref());
+        self.end_pass(builder, GpuPass::Shadows);
+
+        builder
+            .begin_render_pass(framebuffer, SubpassContents::Inline, clear_values)
+            .unwrap()
+            .set_viewport(0, [viewport.clone()])
+            .bind_pipeline_graphics(self.deferred_pipeline.clone());
+
+        if let (Some(draws), Some(deferred_set)) = (&draws, &deferred_set) {
+            scene.render(builder, &self.deferred_pipeline, deferred_set, draws);
+        }
+        self.end_pass(builder, GpuPass::Geometry);
+
+        builder
+            .next_subpass(SubpassContents::Inline)
+            .unwrap()
+            .bind_pipeline_graphics(self.lighting_pipeline.clone())
+            .bind_descriptor_sets(
+                PipelineBindPoint::Graphics,
+                self.lighting_pipeline.layout().clone(),
+                0,
+                lighting_set,
+            )
+            .bind_vertex_buffers(0, self.triangle_system.vertex_buffer.clone())
+            .draw(6, 1, 0, 0)
+            .unwrap();
+        self.end_pass(builder, GpuPass::Lighting);
+    }
+
+    pub fn end_pass(
+        &self,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        pass: GpuPass,
+    ) {
+        if let Some(timer) = &self.gpu_timer {
+            timer.end_pass(builder, pass);
+        }
+    }
+}
+
 pub mod deferred_vert_mod {
     vulkano_shaders::shader! {
         ty: "vertex",
@@ -232,6 +494,8 @@ pub mod deferred_frag_mod {
         ty: "fragment",
         path: "src/shaders/deferred.frag",
         types_meta: {
+            use bytemuck::{Pod, Zeroable};
+
             #[derive(Clone, Copy, Zeroable, Pod)]
         },
     }
@@ -0,0 +1,97 @@
+use std::sync::Arc;
+
+use bytemuck::{Pod, Zeroable};
+use vulkano::{
+    buffer::{cpu_pool::CpuBufferPoolChunk, CpuBufferPool},
+    memory::pool::StdMemoryPool,
+};
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum LightType {
+    Point = 0,
+    Spot = 1,
+}
+
+impl LightType {
+    pub fn get_text(&self) -> &str {
+        match self {
+            LightType::Point => "Point",
+            LightType::Spot => "Spot",
+        }
+    }
+}
+
+pub struct Light {
+    pub light_type: LightType,
+    pub position: [f32; 3],
+    /// Direction the cone of a spot light points to.
+    pub direction: [f32; 3],
+    pub color: [f32; 3],
+    pub intensity: f32,
+    /// Distance at which the light has faded out completely.
+    pub range: f32,
+    /// Angles in radians between the direction and the edges of the cone of a spot light.
+    pub inner_cone_angle: f32,
+    pub outer_cone_angle: f32,
+}
+
+impl Light {
+    pub fn new(light_type: LightType) -> Light {
+        Light {
+            light_type,
+            position: [0.0, 0.0, 0.0],
+            direction: [0.0, 1.0, 0.0],
+            color: [1.0, 1.0, 1.0],
+            intensity: 1.0,
+            range: 500.0,
+            inner_cone_angle: 0.3,
+            outer_cone_angle: 0.5,
+        }
+    }
+}
+
+/// Matches the std430 layout of `Light` in `lighting.frag`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Zeroable, Pod)]
+pub struct LightData {
+    pub position: [f32; 3],
+    pub range: f32,
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub direction: [f32; 3],
+    pub light_type: i32,
+    pub cos_inner_cone: f32,
+    pub cos_outer_cone: f32,
+    pub _padding: [f32; 2],
+}
+
+impl From<&Light> for LightData {
+    fn from(light: &Light) -> Self {
+        LightData {
+            position: light.position,
+            range: light.range,
+            color: light.color,
+            intensity: light.intensity,
+            direction: light.direction,
+            light_type: light.light_type as i32,
+            cos_inner_cone: light.inner_cone_angle.cos(),
+            cos_outer_cone: light.outer_cone_angle.cos(),
+            _padding: [0.0; 2],
+        }
+    }
+}
+
+/// Allocates the lights of a frame from `pool`, whose memory is reused once the frames that used
+/// it have finished.
+pub fn get_light_storage_buffer(
+    pool: &CpuBufferPool<LightData>,
+    lights: &[Light],
+) -> Arc<CpuBufferPoolChunk<LightData, Arc<StdMemoryPool>>> {
+    // Empty buffers are not allowed, so always upload at least one (unused) light.
+    let mut data: Vec<LightData> = lights.iter().map(LightData::from).collect();
+    if data.is_empty() {
+        data.push(LightData::default());
+    }
+
+    pool.chunk(data).unwrap()
+}
@@ -1,2 +1,4 @@
 pub mod deferred;
+pub mod light;
+pub mod shadow;
 pub mod triangle_draw_system;
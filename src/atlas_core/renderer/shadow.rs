@@ -0,0 +1,341 @@
+use std::sync::Arc;
+
+use cgmath::{
+    EuclideanSpace, InnerSpace, Matrix4, MetricSpace, Point3, SquareMatrix, Vector3, Vector4,
+};
+use vulkano::{
+    command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer, SubpassContents},
+    descriptor_set::{PersistentDescriptorSet, WriteDescriptorSet},
+    device::Queue,
+    format::Format,
+    image::{
+        view::{ImageView, ImageViewCreateInfo, ImageViewType},
+        ImageAspects, ImageCreateFlags, ImageDimensions, ImageSubresourceRange, ImageUsage,
+        StorageImage,
+    },
+    pipeline::{
+        graphics::{
+            depth_stencil::{CompareOp, DepthStencilState},
+            input_assembly::InputAssemblyState,
+            vertex_input::BuffersDefinition,
+            viewport::{Viewport, ViewportState},
+        },
+        GraphicsPipeline, Pipeline,
+    },
+    render_pass::{Framebuffer, FramebufferCreateInfo, RenderPass, Subpass},
+    sampler::{BorderColor, Filter, Sampler, SamplerAddressMode, SamplerCreateInfo},
+};
+
+use crate::atlas_core::bounds::Aabb;
+use crate::atlas_core::camera::Camera;
+use crate::atlas_core::mesh::Vertex;
+use crate::atlas_core::scene::{DrawList, Scene};
+
+use super::deferred::RendererParams;
+
+pub const MAX_CASCADES: usize = 4;
+
+/// Blend between logarithmic (1.0) and uniform (0.0) cascade splits.
+const CASCADE_SPLIT_LAMBDA: f32 = 0.75;
+
+/// Maps the OpenGL depth range of cgmath projections to the [0, 1] depth range of Vulkan.
+#[rustfmt::skip]
+const VULKAN_DEPTH_CORRECTION: Matrix4<f32> = Matrix4::new(
+    1.0, 0.0, 0.0, 0.0,
+    0.0, 1.0, 0.0, 0.0,
+    0.0, 0.0, 0.5, 0.0,
+    0.0, 0.0, 0.5, 1.0,
+);
+
+/// Depth maps of the directional light, one array layer per cascade.
+pub struct ShadowMap {
+    pub resolution: u32,
+    pub cascade_count: u32,
+    pub render_pass: Arc<RenderPass>,
+    pub pipeline: Arc<GraphicsPipeline>,
+    pub framebuffers: Vec<Arc<Framebuffer>>,
+    pub view: Arc<ImageView<StorageImage>>,
+    pub sampler: Arc<Sampler>,
+}
+
+pub struct Cascades {
+    /// Transforms world space to the clip space of each cascade.
+    pub matrices: [Matrix4<f32>; MAX_CASCADES],
+    /// View space depth at which each cascade ends.
+    pub splits: [f32; MAX_CASCADES],
+}
+
+impl ShadowMap {
+    pub fn new(queue: &Arc<Queue>, resolution: u32, cascade_count: u32) -> ShadowMap {
+        let device = queue.device();
+        let format = Format::D32_SFLOAT;
+
+        let render_pass = vulkano::single_pass_renderpass!(
+            device.clone(),
+            attachments: {
+                depth: {
+                    load: Clear,
+                    store: Store,
+                    format: format,
+                    samples: 1,
+                }
+            },
+            pass: {
+                color: [],
+                depth_stencil: {depth}
+            }
+        )
+        .unwrap();
+
+        let image = StorageImage::with_usage(
+            device.clone(),
+            ImageDimensions::Dim2d {
+                width: resolution,
+                height: resolution,
+                array_layers: cascade_count,
+            },
+            format,
+            ImageUsage {
+                depth_stencil_attachment: true,
+                sampled: true,
+                ..ImageUsage::none()
+            },
+            ImageCreateFlags::none(),
+            [queue.family()],
+        )
+        .unwrap();
+
+        let subresource_range = |array_layers| ImageSubresourceRange {
+            aspects: ImageAspects {
+                depth: true,
+                ..ImageAspects::none()
+            },
+            mip_levels: 0..1,
+            array_layers,
+        };
+
+        let framebuffers = (0..cascade_count)
+            .map(|layer| {
+                let layer_view = ImageView::new(
+                    image.clone(),
+                    ImageViewCreateInfo {
+                        view_type: ImageViewType::Dim2d,
+                        subresource_range: subresource_range(layer..layer + 1),
+                        ..ImageViewCreateInfo::from_image(&image)
+                    },
+                )
+                .unwrap();
+
+                Framebuffer::new(
+                    render_pass.clone(),
+                    FramebufferCreateInfo {
+                        attachments: vec![layer_view],
+                        ..Default::default()
+                    },
+                )
+                .unwrap()
+            })
+            .collect();
+
+        let view = ImageView::new(
+            image.clone(),
+            ImageViewCreateInfo {
+                view_type: ImageViewType::Dim2dArray,
+                subresource_range: subresource_range(0..cascade_count),
+                ..ImageViewCreateInfo::from_image(&image)
+            },
+        )
+        .unwrap();
+
+        // Comparing in the sampler gives bilinear filtering of the comparison results for free.
+        let sampler = Sampler::new(
+            device.clone(),
+            SamplerCreateInfo {
+                mag_filter: Filter::Linear,
+                min_filter: Filter::Linear,
+                address_mode: [SamplerAddressMode::ClampToBorder; 3],
+                border_color: BorderColor::FloatOpaqueWhite,
+                compare: Some(CompareOp::LessOrEqual),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let shadow_vert = shadow_vert_mod::load(device.clone()).unwrap();
+        let shadow_frag = shadow_frag_mod::load(device.clone()).unwrap();
+
+        let viewport = Viewport {
+            origin: [0.0, 0.0],
+            dimensions: [resolution as f32, resolution as f32],
+            depth_range: 0.0..1.0,
+        };
+
+        let pipeline = GraphicsPipeline::start()
+            .vertex_input_state(BuffersDefinition::new().vertex::<Vertex>())
+            .vertex_shader(shadow_vert.entry_point("main").unwrap(), ())
+            .input_assembly_state(InputAssemblyState::new())
+            .viewport_state(ViewportState::viewport_fixed_scissor_irrelevant([viewport]))
+            .fragment_shader(shadow_frag.entry_point("main").unwrap(), ())
+            .depth_stencil_state(DepthStencilState::simple_depth_test())
+            .render_pass(Subpass::from(render_pass.clone(), 0).unwrap())
+            .build(device.clone())
+            .unwrap();
+
+        ShadowMap {
+            resolution,
+            cascade_count,
+            render_pass,
+            pipeline,
+            framebuffers,
+            view,
+            sampler,
+        }
+    }
+
+    /// Renders the depth of the scene into every cascade. Without `draws`, the cascades are only
+    /// cleared.
+    pub fn draw(
+        &self,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        cascades: &Cascades,
+        scene: &Scene,
+        draws: Option<&DrawList>,
+    ) {
+        let transform_set = draws.map(|draws| {
+            let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
+            PersistentDescriptorSet::new(
+                layout.clone(),
+                [WriteDescriptorSet::buffer(0, draws.transforms.clone())],
+            )
+            .unwrap()
+        });
+
+        for (framebuffer, matrix) in self.framebuffers.iter().zip(cascades.matrices) {
+            builder
+                .begin_render_pass(
+                    framebuffer.clone(),
+                    SubpassContents::Inline,
+                    vec![1f32.into()],
+                )
+                .unwrap()
+                .bind_pipeline_graphics(self.pipeline.clone());
+
+            if let (Some(draws), Some(transform_set)) = (draws, &transform_set) {
+                scene.render_depth(builder, &self.pipeline, matrix, transform_set, draws);
+            }
+
+            builder.end_render_pass().unwrap();
+        }
+    }
+}
+
+/// Splits the view frustum of `camera` into cascades and fits an orthographic projection of the
+/// directional light around each of them.
+///
+/// The projections reach back to the end of `scene_bounds` towards the light, so everything in
+/// the scene can cast shadows into the cascades.
+pub fn compute_cascades(
+    camera: &Camera,
+    params: &RendererParams,
+    scene_bounds: Option<Aabb>,
+) -> Cascades {
+    let near = camera.near;
+    let shadow_distance = params.shadow_distance.min(camera.far_distance());
+
+    let light_direction = Vector3::new(
+        params.directional_direction[0],
+        params.directional_direction[1],
+        params.directional_direction[2],
+    )
+    .normalize();
+    let up = if light_direction.y.abs() > 0.99 {
+        Vector3::unit_z()
+    } else {
+        Vector3::unit_y()
+    };
+
+    let cascade_count = params.cascade_count as usize;
+    let mut cascades = Cascades {
+        matrices: [Matrix4::identity(); MAX_CASCADES],
+        splits: [0.0; MAX_CASCADES],
+    };
+    let mut split_start = near;
+
+    for i in 0..cascade_count {
+        let p = (i + 1) as f32 / cascade_count as f32;
+        let log_split = near * (shadow_distance / near).powf(p);
+        let uniform_split = near + (shadow_distance - near) * p;
+        let split_end =
+            CASCADE_SPLIT_LAMBDA * log_split + (1.0 - CASCADE_SPLIT_LAMBDA) * uniform_split;
+
+        let corners: Vec<Point3<f32>> = camera
+            .get_frustum_slice(split_start)
+            .into_iter()
+            .chain(camera.get_frustum_slice(split_end))
+            .collect();
+        let center = Point3::from_homogeneous(
+            corners
+                .iter()
+                .map(|c| c.to_homogeneous())
+                .sum::<Vector4<f32>>()
+                / 8.0,
+        );
+
+        // Fitting a sphere instead of a box keeps the size of the cascade constant while the
+        // camera rotates, which avoids shimmering edges.
+        let radius = corners
+            .iter()
+            .map(|c| c.distance(center))
+            .fold(0.0, f32::max);
+        let radius = (radius * 16.0).ceil() / 16.0;
+
+        // Casters outside of the sphere, like a roof above the cascade, would be clipped by a
+        // near plane just beyond the sphere.
+        let caster_distance = scene_bounds.map_or(0.0, |bounds| {
+            bounds.extent_along(light_direction) - center.to_vec().dot(light_direction)
+        });
+        let caster_distance = caster_distance.max(radius * 2.0);
+
+        let eye = center + light_direction * caster_distance;
+        let view = Matrix4::look_at_rh(eye, center, up);
+        let mut ortho = cgmath::ortho(
+            -radius,
+            radius,
+            -radius,
+            radius,
+            0.0,
+            caster_distance + radius,
+        );
+
+        // Snap to whole texels, so edges don't shimmer while the camera moves.
+        let half_resolution = params.shadow_resolution as f32 / 2.0;
+        let origin = ortho * view * Vector4::new(0.0, 0.0, 0.0, 1.0) * half_resolution;
+        ortho.w.x += (origin.x.round() - origin.x) / half_resolution;
+        ortho.w.y += (origin.y.round() - origin.y) / half_resolution;
+
+        cascades.matrices[i] = VULKAN_DEPTH_CORRECTION * ortho * view;
+        cascades.splits[i] = split_end;
+        split_start = split_end;
+    }
+
+    cascades
+}
+
+pub mod shadow_vert_mod {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        path: "src/shaders/shadow.vert",
+        types_meta: {
+            use bytemuck::{Pod, Zeroable};
+
+            #[derive(Clone, Copy, Zeroable, Pod)]
+        },
+    }
+}
+
+mod shadow_frag_mod {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/shaders/shadow.frag",
+    }
+}
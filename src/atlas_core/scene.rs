@@ -0,0 +1,738 @@
+use std::{collections::HashMap, mem, sync::Arc};
+
+use cgmath::{Matrix4, SquareMatrix};
+use vulkano::buffer::cpu_pool::CpuBufferPoolChunk;
+use vulkano::buffer::{
+    BufferUsage, CpuAccessibleBuffer, CpuBufferPool, DeviceLocalBuffer, ImmutableBuffer,
+    TypedBufferAccess,
+};
+use vulkano::command_buffer::{
+    AutoCommandBufferBuilder, DrawIndexedIndirectCommand, PrimaryAutoCommandBuffer,
+};
+use vulkano::descriptor_set::layout::DescriptorSetLayout;
+use vulkano::descriptor_set::{PersistentDescriptorSet, WriteDescriptorSet};
+use vulkano::device::{Device, DeviceOwned, Queue};
+use vulkano::image::view::{ImageView, ImageViewAbstract};
+use vulkano::image::ImmutableImage;
+use vulkano::memory::pool::StdMemoryPool;
+use vulkano::pipeline::{GraphicsPipeline, Pipeline, PipelineBindPoint};
+use vulkano::sync::GpuFuture;
+use vulkano::DeviceSize;
+
+use crate::atlas_core::bounds::{Aabb, Frustum};
+use crate::atlas_core::mesh::{Material, MeshBuffer, Vertex};
+use crate::atlas_core::renderer::deferred::deferred_frag_mod::ty::MaterialData;
+use crate::atlas_core::renderer::shadow::shadow_vert_mod;
+use crate::atlas_core::texture::{get_sampler, SamplerSettings};
+
+pub type NodeId = usize;
+
+pub struct Node {
+    pub name: String,
+    /// Transform relative to the parent node.
+    pub transform: Matrix4<f32>,
+    /// Transform relative to the scene root, updated by `Scene::update_transforms`.
+    pub world_transform: Matrix4<f32>,
+    pub parent: Option<NodeId>,
+    pub children: Vec<NodeId>,
+    /// Indices into `Scene::mesh_buffers` that are drawn with this node's transform.
+    pub mesh_buffers: Vec<usize>,
+}
+
+/// Geometry of a model that was uploaded on its own by `Scene::upload_geometry`, until
+/// `Scene::upload` copies it into the buffers of the scene.
+pub struct PendingGeometry {
+    pub vertex_buffer: Arc<ImmutableBuffer<[Vertex]>>,
+    pub index_buffer: Arc<ImmutableBuffer<[u32]>>,
+    /// Where the geometry goes in the buffers of the scene.
+    pub first_vertex: u32,
+    pub first_index: u32,
+}
+
+/// The geometry of all meshes of a scene on the GPU, created by `Scene::upload`.
+pub struct SceneBuffers {
+    pub vertex_buffer: Arc<DeviceLocalBuffer<[Vertex]>>,
+    pub index_buffer: Arc<DeviceLocalBuffer<[u32]>>,
+}
+
+/// The materials of a scene, created by `Scene::upload`.
+pub struct MaterialSet {
+    pub material_buffer: Arc<CpuAccessibleBuffer<[MaterialData]>>,
+    /// The textures used by the materials, which refer to them by their index in this list.
+    pub textures: Vec<Arc<ImageView<ImmutableImage>>>,
+    /// The materials, and a bindless array of the textures they use.
+    pub set: Arc<PersistentDescriptorSet>,
+    /// The settings of the sampler in `set`.
+    pub sampler: SamplerSettings,
+}
+
+fn create_material_set(
+    layout: &Arc<DescriptorSetLayout>,
+    material_buffer: &Arc<CpuAccessibleBuffer<[MaterialData]>>,
+    textures: &[Arc<ImageView<ImmutableImage>>],
+    sampler: &SamplerSettings,
+) -> Arc<PersistentDescriptorSet> {
+    let sampler = get_sampler(layout.device(), sampler);
+    PersistentDescriptorSet::new_variable(
+        layout.clone(),
+        textures.len() as u32,
+        [
+            WriteDescriptorSet::buffer(0, material_buffer.clone()),
+            WriteDescriptorSet::image_view_sampler_array(
+                1,
+                0,
+                textures.iter().map(|texture| {
+                    (
+                        texture.clone() as Arc<dyn ImageViewAbstract>,
+                        sampler.clone(),
+                    )
+                }),
+            ),
+        ],
+    )
+    .unwrap()
+}
+
+/// The indirect draw commands of a frame. The first instance of every draw is its index into
+/// `transforms` and `materials`.
+pub struct DrawList {
+    pub commands: Arc<CpuBufferPoolChunk<DrawIndexedIndirectCommand, Arc<StdMemoryPool>>>,
+    pub transforms: Arc<CpuBufferPoolChunk<[[f32; 4]; 4], Arc<StdMemoryPool>>>,
+    pub materials: Arc<CpuBufferPoolChunk<u32, Arc<StdMemoryPool>>>,
+}
+
+/// Buffers the draw lists are allocated from. Their memory is reused once the frames that drew
+/// them have finished, instead of allocating new buffers every frame.
+pub struct DrawListPools {
+    pub commands: CpuBufferPool<DrawIndexedIndirectCommand>,
+    pub transforms: CpuBufferPool<[[f32; 4]; 4]>,
+    pub materials: CpuBufferPool<u32>,
+}
+
+impl DrawListPools {
+    pub fn new(device: &Arc<Device>) -> DrawListPools {
+        DrawListPools {
+            commands: CpuBufferPool::new(device.clone(), BufferUsage::indirect_buffer()),
+            transforms: CpuBufferPool::new(device.clone(), BufferUsage::storage_buffer()),
+            materials: CpuBufferPool::new(device.clone(), BufferUsage::storage_buffer()),
+        }
+    }
+}
+
+/// Number of mesh buffers drawn by the camera and culled because they are outside of its frustum.
+#[derive(Clone, Copy, Default)]
+pub struct CullingStats {
+    pub drawn: usize,
+    pub culled: usize,
+}
+
+/// A hierarchy of nodes stored in a flat list.
+///
+/// Nodes can only be attached to a parent that already exists, so a parent always precedes its
+/// children in `nodes`.
+pub struct Scene {
+    pub nodes: Vec<Node>,
+    pub root: NodeId,
+    /// The vertices and indices of a model while it is loaded, which are moved to the GPU by
+    /// `upload_geometry`.
+    pub vertices: Vec<Vertex>,
+    pub indices: Vec<u32>,
+    /// Number of vertices and indices on the GPU. The mesh buffers are ranges of these.
+    pub vertex_count: u32,
+    pub index_count: u32,
+    pub materials: Vec<Material>,
+    pub mesh_buffers: Vec<MeshBuffer>,
+    /// Uploaded geometry that isn't part of `buffers` yet.
+    pub pending_geometry: Vec<PendingGeometry>,
+    /// `None` until the scene is uploaded.
+    pub buffers: Option<SceneBuffers>,
+    /// `None` until the scene is uploaded, and again after adding another scene to it.
+    pub material_set: Option<MaterialSet>,
+}
+
+impl Scene {
+    pub fn new() -> Scene {
+        let mut scene = Scene {
+            nodes: vec![],
+            root: 0,
+            vertices: vec![],
+            indices: vec![],
+            vertex_count: 0,
+            index_count: 0,
+            materials: vec![],
+            mesh_buffers: vec![],
+            pending_geometry: vec![],
+            buffers: None,
+            material_set: None,
+        };
+        scene.root = scene.add_node(None, "root", Matrix4::identity());
+        scene
+    }
+
+    pub fn add_node(
+        &mut self,
+        parent: Option<NodeId>,
+        name: &str,
+        transform: Matrix4<f32>,
+    ) -> NodeId {
+        let id = self.nodes.len();
+
+        if let Some(parent) = parent {
+            self.nodes[parent].children.push(id);
+        }
+
+        self.nodes.push(Node {
+            name: name.to_owned(),
+            transform,
+            world_transform: transform,
+            parent,
+            children: vec![],
+            mesh_buffers: vec![],
+        });
+
+        id
+    }
+
+    /// Moves all nodes, meshes and materials of `other` into this scene, below `parent`.
+    ///
+    /// Returns the id of the root node of `other` in this scene. The geometry of `other` has to
+    /// be on the GPU already, and the scene has to be uploaded again before the new meshes are
+    /// drawn.
+    pub fn add_scene(&mut self, other: Scene, parent: NodeId) -> NodeId {
+        debug_assert!(
+            other.vertices.is_empty() && other.indices.is_empty(),
+            "the geometry of an added scene has to be uploaded first"
+        );
+
+        let node_offset = self.nodes.len();
+        let mesh_buffer_offset = self.mesh_buffers.len();
+        let first_index = self.index_count;
+        let vertex_offset = self.vertex_count;
+        let material_offset = self.materials.len();
+
+        for mut node in other.nodes {
+            node.parent = Some(node.parent.map_or(parent, |p| p + node_offset));
+            node.children.iter_mut().for_each(|c| *c += node_offset);
+            node.mesh_buffers
+                .iter_mut()
+                .for_each(|m| *m += mesh_buffer_offset);
+            self.nodes.push(node);
+        }
+
+        let root = other.root + node_offset;
+        self.nodes[parent].children.push(root);
+        self.mesh_buffers.extend(
+            other
+                .mesh_buffers
+                .into_iter()
+                .map(|mesh_buffer| MeshBuffer {
+                    first_index: mesh_buffer.first_index + first_index,
+                    vertex_offset: mesh_buffer.vertex_offset + vertex_offset as i32,
+                    material: mesh_buffer.material + material_offset,
+                    ..mesh_buffer
+                }),
+        );
+        self.pending_geometry
+            .extend(
+                other
+                    .pending_geometry
+                    .into_iter()
+                    .map(|geometry| PendingGeometry {
+                        first_vertex: geometry.first_vertex + vertex_offset,
+                        first_index: geometry.first_index + first_index,
+                        ..geometry
+                    }),
+            );
+        self.vertex_count += other.vertex_count;
+        self.index_count += other.index_count;
+        self.materials.extend(other.materials);
+        self.material_set = None;
+
+        root
+    }
+
+    /// Copies the subtree starting at `source` below `parent`.
+    ///
+    /// The copies draw the same mesh buffers, so instances don't use any additional GPU memory.
+    pub fn instantiate(&mut self, source: NodeId, parent: NodeId) -> NodeId {
+        let name = self.nodes[source].name.clone();
+        let transform = self.nodes[source].transform;
+        let mesh_buffers = self.nodes[source].mesh_buffers.clone();
+        let children = self.nodes[source].children.clone();
+
+        let id = self.add_node(Some(parent), &name, transform);
+        self.nodes[id].mesh_buffers = mesh_buffers;
+
+        for child in children {
+            self.instantiate(child, id);
+        }
+
+        id
+    }
+
+    /// Propagates the local transforms down the hierarchy into `world_transform`.
+    pub fn update_transforms(&mut self) {
+        for id in 0..self.nodes.len() {
+            self.nodes[id].world_transform = match self.nodes[id].parent {
+                Some(parent) => self.nodes[parent].world_transform * self.nodes[id].transform,
+                None => self.nodes[id].transform,
+            };
+        }
+    }
+
+    /// Returns the world space box around the meshes of `node` and its descendants, using the
+    /// world transforms of the last `update_transforms`.
+    pub fn get_bounds(&self, node: NodeId) -> Option<Aabb> {
+        let node = &self.nodes[node];
+        let meshes = node.mesh_buffers.iter().map(|&mesh| {
+            self.mesh_buffers[mesh]
+                .aabb
+                .transform(&node.world_transform)
+        });
+
+        meshes
+            .chain(
+                node.children
+                    .iter()
+                    .filter_map(|&child| self.get_bounds(child)),
+            )
+            .reduce(|a, b| a.union(&b))
+    }
+
+    /// Moves `vertices` and `indices` into buffers of their own in device-local memory, and waits
+    /// until they are copied. Meant for loader threads, so rendering doesn't wait for the copy.
+    pub fn upload_geometry(&mut self, queue: &Arc<Queue>) {
+        if self.vertices.is_empty() || self.indices.is_empty() {
+            return;
+        }
+
+        // Takes the vectors, so their memory is freed after the copy.
+        let vertices = mem::take(&mut self.vertices);
+        let indices = mem::take(&mut self.indices);
+        let (vertex_count, index_count) = (vertices.len() as u32, indices.len() as u32);
+
+        let (vertex_buffer, vertex_future) =
+            ImmutableBuffer::from_iter(vertices, BufferUsage::transfer_source(), queue.clone())
+                .unwrap();
+        let (index_buffer, index_future) =
+            ImmutableBuffer::from_iter(indices, BufferUsage::transfer_source(), queue.clone())
+                .unwrap();
+
+        vertex_future
+            .join(index_future)
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+
+        self.pending_geometry.push(PendingGeometry {
+            vertex_buffer,
+            index_buffer,
+            first_vertex: self.vertex_count,
+            first_index: self.index_count,
+        });
+        self.vertex_count += vertex_count;
+        self.index_count += index_count;
+    }
+
+    /// Records copying the pending geometry into the vertex and index buffer of the scene, and
+    /// creates the material set if it is missing or the sampler settings changed.
+    ///
+    /// `layout` is the layout of the material set of the deferred pipeline. Textures that don't
+    /// fit into its texture array are replaced by the first texture.
+    pub fn upload(
+        &mut self,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        layout: &Arc<DescriptorSetLayout>,
+        sampler: &SamplerSettings,
+    ) {
+        if !self.pending_geometry.is_empty() {
+            self.buffers = Some(self.merge_geometry(builder, layout.device()));
+        }
+
+        match &mut self.material_set {
+            Some(material_set) if material_set.sampler != *sampler => {
+                material_set.set = create_material_set(
+                    layout,
+                    &material_set.material_buffer,
+                    &material_set.textures,
+                    sampler,
+                );
+                material_set.sampler = *sampler;
+            }
+            Some(_) => {}
+            None if self.materials.is_empty() => {}
+            None => self.material_set = Some(self.create_materials(layout, sampler)),
+        }
+    }
+
+    /// Creates buffers that fit all geometry, and records copying the current buffers and the
+    /// pending geometry into them.
+    ///
+    /// The buffers are replaced instead of written to, as the frames in flight may still read
+    /// them. The old ones are freed once those frames finish.
+    fn merge_geometry(
+        &mut self,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        device: &Arc<Device>,
+    ) -> SceneBuffers {
+        let usage = |usage: BufferUsage| BufferUsage {
+            transfer_source: true,
+            transfer_destination: true,
+            ..usage
+        };
+        let vertex_buffer = DeviceLocalBuffer::<[Vertex]>::array(
+            device.clone(),
+            self.vertex_count as DeviceSize,
+            usage(BufferUsage::vertex_buffer()),
+            device.active_queue_families(),
+        )
+        .unwrap();
+        let index_buffer = DeviceLocalBuffer::<[u32]>::array(
+            device.clone(),
+            self.index_count as DeviceSize,
+            usage(BufferUsage::index_buffer()),
+            device.active_queue_families(),
+        )
+        .unwrap();
+
+        if let Some(buffers) = &self.buffers {
+            builder
+                .copy_buffer_dimensions(
+                    buffers.vertex_buffer.clone(),
+                    0,
+                    vertex_buffer.clone(),
+                    0,
+                    buffers.vertex_buffer.len(),
+                )
+                .unwrap()
+                .copy_buffer_dimensions(
+                    buffers.index_buffer.clone(),
+                    0,
+                    index_buffer.clone(),
+                    0,
+                    buffers.index_buffer.len(),
+                )
+                .unwrap();
+        }
+
+        for geometry in self.pending_geometry.drain(..) {
+            builder
+                .copy_buffer_dimensions(
+                    geometry.vertex_buffer.clone(),
+                    0,
+                    vertex_buffer.clone(),
+                    geometry.first_vertex as DeviceSize,
+                    geometry.vertex_buffer.len(),
+                )
+                .unwrap()
+                .copy_buffer_dimensions(
+                    geometry.index_buffer.clone(),
+                    0,
+                    index_buffer.clone(),
+                    geometry.first_index as DeviceSize,
+                    geometry.index_buffer.len(),
+                )
+                .unwrap();
+        }
+
+        SceneBuffers {
+            vertex_buffer,
+            index_buffer,
+        }
+    }
+
+    fn create_materials(
+        &self,
+        layout: &Arc<DescriptorSetLayout>,
+        sampler: &SamplerSettings,
+    ) -> MaterialSet {
+        // Materials often share textures, which only need one entry in the array.
+        let mut textures: Vec<Arc<ImageView<ImmutableImage>>> = vec![];
+        let mut texture_indices = HashMap::new();
+        let max_textures = layout.variable_descriptor_count() as usize;
+        let mut skipped_textures = 0;
+        let mut get_texture_index = |texture: &Arc<ImageView<ImmutableImage>>| {
+            if let Some(&index) = texture_indices.get(&Arc::as_ptr(texture)) {
+                return index;
+            }
+            if textures.len() == max_textures {
+                // The array is full, so the material uses the first texture instead.
+                skipped_textures += 1;
+                return 0;
+            }
+
+            textures.push(texture.clone());
+            let index = textures.len() as u32 - 1;
+            texture_indices.insert(Arc::as_ptr(texture), index);
+            index
+        };
+
+        let materials: Vec<MaterialData> = self
+            .materials
+            .iter()
+            .map(|material| {
+                let [base_color, metallic_roughness, occlusion, emissive, normal] =
+                    material.textures.each_ref().map(&mut get_texture_index);
+
+                MaterialData {
+                    base_color_texture: base_color,
+                    metallic_roughness_texture: metallic_roughness,
+                    occlusion_texture: occlusion,
+                    emissive_texture: emissive,
+                    normal_texture: normal,
+                    ..material.data
+                }
+            })
+            .collect();
+
+        if skipped_textures > 0 {
+            eprintln!(
+                "The scene uses more than {} textures, {} material textures were replaced",
+                max_textures, skipped_textures
+            );
+        }
+
+        // The materials are small and rarely change, so they stay in host-visible memory instead
+        // of waiting for a copy.
+        let material_buffer = CpuAccessibleBuffer::from_iter(
+            layout.device().clone(),
+            BufferUsage::storage_buffer(),
+            false,
+            materials,
+        )
+        .unwrap();
+
+        MaterialSet {
+            set: create_material_set(layout, &material_buffer, &textures, sampler),
+            material_buffer,
+            textures,
+            sampler: *sampler,
+        }
+    }
+
+    /// Creates one indirect draw for every mesh buffer of every node, using the current world
+    /// transforms. With a `frustum`, mesh buffers outside of it are skipped.
+    ///
+    /// The draw list is `None` if there is nothing to draw, or the scene isn't uploaded.
+    pub fn get_draw_list(
+        &self,
+        pools: &DrawListPools,
+        frustum: Option<&Frustum>,
+    ) -> (Option<DrawList>, CullingStats) {
+        let mut stats = CullingStats::default();
+        if self.buffers.is_none() || self.material_set.is_none() {
+            return (None, stats);
+        }
+
+        let mut commands = vec![];
+        let mut transforms: Vec<[[f32; 4]; 4]> = vec![];
+        let mut materials = vec![];
+
+        for node in &self.nodes {
+            for &index in &node.mesh_buffers {
+                let mesh_buffer = &self.mesh_buffers[index];
+
+                if let Some(frustum) = frustum {
+                    // The sphere test is cheaper, the box test fits elongated meshes better.
+                    let transform = &node.world_transform;
+                    if !frustum.intersects_sphere(&mesh_buffer.sphere.transform(transform))
+                        || !frustum.intersects_aabb(&mesh_buffer.aabb.transform(transform))
+                    {
+                        stats.culled += 1;
+                        continue;
+                    }
+                }
+
+                commands.push(DrawIndexedIndirectCommand {
+                    index_count: mesh_buffer.index_count,
+                    instance_count: 1,
+                    first_index: mesh_buffer.first_index,
+                    vertex_offset: mesh_buffer.vertex_offset,
+                    first_instance: commands.len() as u32,
+                });
+                transforms.push(node.world_transform.into());
+                materials.push(mesh_buffer.material as u32);
+            }
+        }
+
+        stats.drawn = commands.len();
+        if commands.is_empty() {
+            return (None, stats);
+        }
+
+        let draws = DrawList {
+            commands: pools.commands.chunk(commands).unwrap(),
+            transforms: pools.transforms.chunk(transforms).unwrap(),
+            materials: pools.materials.chunk(materials).unwrap(),
+        };
+
+        (Some(draws), stats)
+    }
+
+    /// Draws all meshes with a single indirect draw. `general_set` holds the camera and the
+    /// transforms and materials of `draws`.
+    pub fn render(
+        &self,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        pipeline: &Arc<GraphicsPipeline>,
+        general_set: &Arc<PersistentDescriptorSet>,
+        draws: &DrawList,
+    ) {
+        let buffers = self.buffers.as_ref().unwrap();
+
+        builder
+            .bind_descriptor_sets(
+                PipelineBindPoint::Graphics,
+                pipeline.layout().clone(),
+                0,
+                vec![
+                    general_set.clone(),
+                    self.material_set.as_ref().unwrap().set.clone(),
+                ],
+            )
+            .bind_vertex_buffers(0, buffers.vertex_buffer.clone())
+            .bind_index_buffer(buffers.index_buffer.clone())
+            .draw_indexed_indirect(draws.commands.clone())
+            .unwrap();
+    }
+
+    /// Draws only the positions of all meshes, for depth-only passes. `transform_set` holds the
+    /// transforms of `draws`.
+    pub fn render_depth(
+        &self,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        pipeline: &Arc<GraphicsPipeline>,
+        view_proj: Matrix4<f32>,
+        transform_set: &Arc<PersistentDescriptorSet>,
+        draws: &DrawList,
+    ) {
+        let buffers = self.buffers.as_ref().unwrap();
+        let push_constants = shadow_vert_mod::ty::PushConstants {
+            view_proj: view_proj.into(),
+        };
+
+        builder
+            .push_constants(pipeline.layout().clone(), 0, push_constants)
+            .bind_descriptor_sets(
+                PipelineBindPoint::Graphics,
+                pipeline.layout().clone(),
+                0,
+                transform_set.clone(),
+            )
+            .bind_vertex_buffers(0, buffers.vertex_buffer.clone())
+            .bind_index_buffer(buffers.index_buffer.clone())
+            .draw_indexed_indirect(draws.commands.clone())
+            .unwrap();
+    }
+}
+
+impl Default for Scene {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Point3, Vector3};
+
+    use super::*;
+    use crate::atlas_core::bounds::BoundingSphere;
+
+    fn mesh_buffer(first_index: u32, index_count: u32, vertex_offset: i32) -> MeshBuffer {
+        let aabb = Aabb::from_points([Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0)]);
+        MeshBuffer {
+            first_index,
+            index_count,
+            vertex_offset,
+            material: 0,
+            aabb,
+            sphere: BoundingSphere::from_points(&[aabb.min, aabb.max], &aabb),
+        }
+    }
+
+    /// A model with a root, a child and a grandchild, where the child draws one triangle.
+    fn model(vertex_count: u32) -> Scene {
+        let mut scene = Scene::new();
+        let root = scene.root;
+        let child = scene.add_node(Some(root), "child", Matrix4::identity());
+        scene.add_node(Some(child), "grandchild", Matrix4::identity());
+
+        // As if the geometry was uploaded.
+        scene.vertex_count = vertex_count;
+        scene.index_count = 3;
+        scene.mesh_buffers.push(mesh_buffer(0, 3, 0));
+        scene.nodes[child].mesh_buffers.push(0);
+        scene
+    }
+
+    fn assert_parents_precede_children(scene: &Scene) {
+        for (id, node) in scene.nodes.iter().enumerate() {
+            if let Some(parent) = node.parent {
+                assert!(parent < id, "node {} precedes its parent {}", id, parent);
+                assert!(scene.nodes[parent].children.contains(&id));
+            }
+            for &child in &node.children {
+                assert_eq!(scene.nodes[child].parent, Some(id));
+            }
+        }
+    }
+
+    #[test]
+    fn add_scene_offsets_nodes_and_mesh_buffers() {
+        let mut scene = model(3);
+        let root = scene.root;
+        let added = scene.add_scene(model(4), root);
+
+        assert_eq!(scene.nodes.len(), 6);
+        assert_eq!(added, 3);
+        assert_eq!(scene.nodes[added].parent, Some(root));
+        assert_parents_precede_children(&scene);
+
+        assert_eq!(scene.nodes[4].mesh_buffers, vec![1]);
+        let mesh_buffer = &scene.mesh_buffers[1];
+        assert_eq!(mesh_buffer.first_index, 3);
+        assert_eq!(mesh_buffer.vertex_offset, 3);
+        assert_eq!(scene.vertex_count, 7);
+        assert_eq!(scene.index_count, 6);
+    }
+
+    #[test]
+    fn instantiate_shares_mesh_buffers() {
+        let mut scene = Scene::new();
+        let root = scene.root;
+        let model = scene.add_scene(model(3), root);
+        let instance = scene.instantiate(model, root);
+
+        assert_eq!(scene.nodes.len(), 7);
+        assert_eq!(scene.mesh_buffers.len(), 1);
+        assert_eq!(scene.nodes[instance].parent, Some(root));
+        assert_parents_precede_children(&scene);
+
+        let instance_child = scene.nodes[instance].children[0];
+        assert_eq!(scene.nodes[instance_child].mesh_buffers, vec![0]);
+        assert_eq!(scene.nodes[instance_child].children.len(), 1);
+    }
+
+    #[test]
+    fn update_transforms_applies_parents_first() {
+        let mut scene = Scene::new();
+        let root = scene.root;
+        let model = scene.add_scene(model(3), root);
+        scene.nodes[model].transform = Matrix4::from_translation(Vector3::new(1.0, 0.0, 0.0));
+        let child = scene.nodes[model].children[0];
+        scene.nodes[child].transform = Matrix4::from_scale(2.0);
+        let grandchild = scene.nodes[child].children[0];
+        scene.nodes[grandchild].transform = Matrix4::from_translation(Vector3::new(0.0, 1.0, 0.0));
+
+        let instance = scene.instantiate(model, root);
+        scene.nodes[instance].transform = Matrix4::from_translation(Vector3::new(5.0, 0.0, 0.0));
+        scene.update_transforms();
+
+        let origin = |id: NodeId| scene.nodes[id].world_transform.w.truncate();
+        assert_eq!(origin(grandchild), Vector3::new(1.0, 2.0, 0.0));
+        let instance_grandchild = scene.nodes[scene.nodes[instance].children[0]].children[0];
+        assert_eq!(origin(instance_grandchild), Vector3::new(5.0, 2.0, 0.0));
+    }
+}
@@ -1,102 +1,441 @@
+use crate::atlas_core::decoder::{DecodedImage, DecoderRegistry, ImageData};
+use crate::atlas_core::error::AssetError;
 use crate::atlas_core::mesh::Texture;
-use crate::atlas_core::System;
-use png::ColorType;
-use std::io::prelude::*;
-use std::{fs::File, io::Cursor, sync::Arc};
-use vulkano::descriptor_set::layout::DescriptorSetLayout;
-use vulkano::descriptor_set::PersistentDescriptorSet;
-use vulkano::descriptor_set::WriteDescriptorSet;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::{fs, sync::Arc};
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, PrimaryCommandBuffer};
 use vulkano::sampler::Sampler;
-use vulkano::sampler::{Filter, SamplerAddressMode, SamplerCreateInfo};
+use vulkano::sampler::{
+    Filter, SamplerAddressMode, SamplerCreateInfo, SamplerMipmapMode, LOD_CLAMP_NONE,
+};
 use vulkano::{
-    device::Queue,
+    device::{Device, Queue},
     format::Format,
-    image::{view::ImageView, ImageDimensions, ImmutableImage, MipmapsCount},
+    image::{
+        view::ImageView, ImageCreateFlags, ImageDimensions, ImageLayout, ImageUsage, ImmutableImage,
+    },
 };
 
-#[allow(dead_code)]
-pub fn load_png(queue: &Arc<Queue>, data: &Vec<u8>) -> Texture {
-    let cursor = Cursor::new(data);
-    let decoder = png::Decoder::new(cursor);
-    let mut reader = decoder.read_info().unwrap();
-    let info = reader.info();
+/// Color textures should use an sRGB `format`, other data such as normal maps a linear one.
+pub fn load_image(
+    queue: &Arc<Queue>,
+    image: DecodedImage,
+    format: Format,
+) -> Result<Texture, AssetError> {
+    let DecodedImage {
+        width,
+        height,
+        data,
+    } = image;
+    let srgb = format == Format::R8G8B8A8_SRGB;
 
-    let (width, height) = (info.width, info.height);
-    let array_layers = 1;
+    match data {
+        ImageData::Rgba(rgba) => {
+            let levels = generate_mipmaps(width, height, rgba, srgb);
+            Ok(upload_image(queue, width, height, format, levels))
+        }
+        ImageData::Native {
+            linear,
+            srgb: srgb_format,
+            levels,
+        } => {
+            let native_format = if srgb { srgb_format } else { linear };
+            let device = queue.device();
+            if native_format.compression().is_some()
+                && !device.enabled_features().texture_compression_bc
+            {
+                return Err(AssetError::UnsupportedContent(format!(
+                    "{:?} textures on this device",
+                    native_format
+                )));
+            }
 
-    let color_type = info.color_type;
-    let has_alpha = color_type == ColorType::Rgba;
-    let channel_count = if has_alpha { 4 } else { 3 };
+            Ok(upload_image(queue, width, height, native_format, levels))
+        }
+    }
+}
 
-    let mut image_data = Vec::new();
-    image_data.resize((info.width * info.height * channel_count) as usize, 0);
-    reader.next_frame(&mut image_data).unwrap();
+/// Creates a 1x1 texture of a single color, used when a material has no texture.
+pub fn load_solid_color(queue: &Arc<Queue>, color: [u8; 4], format: Format) -> Texture {
+    upload_image(queue, 1, 1, format, vec![color.to_vec()])
+}
 
-    let image_data_alpha: Vec<u8> = if has_alpha {
-        image_data
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
     } else {
-        image_data
-            .chunks(3)
-            .map(|x| [x[0], x[1], x[2], 255])
-            .flatten()
-            .collect()
-    };
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// The texels of a row or column of `size` texels that are averaged into texel `i` of the next
+/// level, with their weights. Odd sizes use 3 texels, weighted by how much of each one the next
+/// texel covers, so every texel contributes equally to the next level.
+fn get_taps(size: usize, i: usize) -> Vec<(usize, f32)> {
+    let half = size / 2;
+    if size == 1 {
+        vec![(0, 1.0)]
+    } else if size == half * 2 {
+        vec![(2 * i, 0.5), (2 * i + 1, 0.5)]
+    } else {
+        let size = size as f32;
+        vec![
+            (2 * i, (half - i) as f32 / size),
+            (2 * i + 1, half as f32 / size),
+            (2 * i + 2, (i + 1) as f32 / size),
+        ]
+    }
+}
+
+/// Builds the full mip chain of an RGBA8 image, down to 1x1, by averaging blocks of 2x2 texels,
+/// or 3 texels along odd sizes.
+///
+/// The color channels of sRGB images are averaged in linear space, so the mips don't darken.
+pub fn generate_mipmaps(width: u32, height: u32, rgba: Vec<u8>, srgb: bool) -> Vec<Vec<u8>> {
+    let to_linear: Vec<f32> = (0..=255)
+        .map(|value| {
+            let value = value as f32 / 255.0;
+            if srgb {
+                srgb_to_linear(value)
+            } else {
+                value
+            }
+        })
+        .collect();
+
+    let mut levels = vec![rgba];
+    let (mut width, mut height) = (width as usize, height as usize);
+
+    while width > 1 || height > 1 {
+        let (next_width, next_height) = ((width / 2).max(1), (height / 2).max(1));
+        let previous = levels.last().unwrap();
+        let mut level = Vec::with_capacity(next_width * next_height * 4);
+        let column_taps: Vec<_> = (0..next_width).map(|x| get_taps(width, x)).collect();
+
+        for y in 0..next_height {
+            let row_taps = get_taps(height, y);
+            for taps in &column_taps {
+                let texels: Vec<(usize, f32)> = row_taps
+                    .iter()
+                    .flat_map(|&(ty, y_weight)| {
+                        taps.iter().map(move |&(tx, x_weight)| {
+                            ((ty * width + tx) * 4, y_weight * x_weight)
+                        })
+                    })
+                    .collect();
+
+                for channel in 0..4 {
+                    let average: f32 = texels
+                        .iter()
+                        .map(|&(texel, weight)| {
+                            let value = previous[texel + channel];
+                            let value = if channel < 3 {
+                                to_linear[value as usize]
+                            } else {
+                                value as f32 / 255.0
+                            };
+                            value * weight
+                        })
+                        .sum();
 
-    let (image, future) = ImmutableImage::from_iter(
-        image_data_alpha,
+                    let value = if srgb && channel < 3 {
+                        linear_to_srgb(average)
+                    } else {
+                        average
+                    };
+                    level.push((value * 255.0).round() as u8);
+                }
+            }
+        }
+
+        levels.push(level);
+        width = next_width;
+        height = next_height;
+    }
+
+    levels
+}
+
+/// Uploads an image in `format`, with one entry in `levels` per mip level.
+///
+/// The copies are recorded on `queue`, which only needs to support transfers.
+pub fn upload_image(
+    queue: &Arc<Queue>,
+    width: u32,
+    height: u32,
+    format: Format,
+    levels: Vec<Vec<u8>>,
+) -> Texture {
+    let device = queue.device();
+
+    let (image, initializer) = ImmutableImage::uninitialized(
+        device.clone(),
         ImageDimensions::Dim2d {
             width,
             height,
-            array_layers,
+            array_layers: 1,
+        },
+        format,
+        levels.len() as u32,
+        ImageUsage {
+            transfer_destination: true,
+            sampled: true,
+            ..ImageUsage::none()
         },
-        MipmapsCount::One,
-        Format::R8G8B8A8_SRGB,
-        queue.clone(),
+        ImageCreateFlags::none(),
+        ImageLayout::ShaderReadOnlyOptimal,
+        device.active_queue_families(),
+    )
+    .unwrap();
+
+    let mut builder = AutoCommandBufferBuilder::primary(
+        device.clone(),
+        queue.family(),
+        CommandBufferUsage::OneTimeSubmit,
     )
     .unwrap();
 
+    for (mip_level, data) in levels.into_iter().enumerate() {
+        let mip_level = mip_level as u32;
+        let buffer = CpuAccessibleBuffer::from_iter(
+            device.clone(),
+            BufferUsage::transfer_source(),
+            false,
+            data,
+        )
+        .unwrap();
+
+        builder
+            .copy_buffer_to_image_dimensions(
+                buffer,
+                initializer.clone(),
+                [0, 0, 0],
+                [(width >> mip_level).max(1), (height >> mip_level).max(1), 1],
+                0,
+                1,
+                mip_level,
+            )
+            .unwrap();
+    }
+
+    let future = builder.build().unwrap().execute(queue.clone()).unwrap();
+
     Texture {
         image: ImageView::new_default(image).unwrap(),
         future,
     }
 }
 
-#[allow(dead_code)]
-pub fn load_png_file(queue: &Arc<Queue>, path: &str) -> Texture {
-    let mut f = File::open(path).expect("Could not open file");
-    let mut png_bytes = Vec::new();
+/// Loads a texture file with the decoder of its format, see `load_image`.
+pub fn load_image_file(
+    queue: &Arc<Queue>,
+    decoders: &DecoderRegistry,
+    path: &Path,
+    format: Format,
+) -> Result<Texture, AssetError> {
+    let bytes = fs::read(path).map_err(|error| AssetError::Io {
+        path: path.into(),
+        error,
+    })?;
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default();
 
-    f.read_to_end(&mut png_bytes)
-        .expect("Could not read png file");
+    load_image(queue, decoders.decode(extension, &bytes)?, format)
+}
 
-    load_png(queue, &png_bytes)
+#[derive(Clone, Copy, PartialEq)]
+pub struct SamplerSettings {
+    /// Blend between mip levels, instead of using the nearest one.
+    pub trilinear: bool,
+    /// Maximum anisotropy, clamped to what the device supports. `None` disables anisotropic
+    /// filtering.
+    pub anisotropy: Option<f32>,
 }
 
-pub fn get_descriptor_set(
-    system: &System,
-    layout: &Arc<DescriptorSetLayout>,
-    texture: Texture,
-) -> Arc<PersistentDescriptorSet> {
-    let image = texture.image;
+impl Default for SamplerSettings {
+    fn default() -> Self {
+        SamplerSettings {
+            trilinear: true,
+            anisotropy: Some(16.0),
+        }
+    }
+}
 
-    let sampler = Sampler::new(
-        system.device.clone(),
+pub fn get_sampler(device: &Arc<Device>, settings: &SamplerSettings) -> Arc<Sampler> {
+    let max_anisotropy = device.physical_device().properties().max_sampler_anisotropy;
+    let anisotropy = settings
+        .anisotropy
+        .filter(|_| device.enabled_features().sampler_anisotropy)
+        .map(|anisotropy| anisotropy.clamp(1.0, max_anisotropy));
+
+    Sampler::new(
+        device.clone(),
         SamplerCreateInfo {
             mag_filter: Filter::Linear,
             min_filter: Filter::Linear,
+            mipmap_mode: if settings.trilinear {
+                SamplerMipmapMode::Linear
+            } else {
+                SamplerMipmapMode::Nearest
+            },
             address_mode: [SamplerAddressMode::Repeat; 3],
+            anisotropy,
+            lod: 0.0..=LOD_CLAMP_NONE,
             ..Default::default()
         },
     )
-    .unwrap();
-
-    PersistentDescriptorSet::new(
-        layout.clone(),
-        [WriteDescriptorSet::image_view_sampler(
-            0,
-            image.clone(),
-            sampler.clone(),
-        )],
-    )
     .unwrap()
 }
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum TextureSource {
+    /// Absolute path of a texture file.
+    File(PathBuf),
+    /// Hash of the data of a texture embedded in a model, see `hash_data`.
+    Embedded(u64),
+    SolidColor([u8; 4]),
+}
+
+pub fn hash_data(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Textures that failed to load are cached as well, so they are only attempted once.
+pub type CachedTexture = Result<Arc<ImageView<ImmutableImage>>, Arc<AssetError>>;
+
+/// Shares textures between all materials that use them, so each one is decoded and uploaded once.
+#[derive(Default)]
+pub struct TextureCache {
+    /// Used to decode the textures of all models loaded through this cache.
+    pub decoders: DecoderRegistry,
+    textures: Mutex<HashMap<(TextureSource, Format), Arc<OnceLock<CachedTexture>>>>,
+}
+
+impl TextureCache {
+    pub fn new(decoders: DecoderRegistry) -> TextureCache {
+        TextureCache {
+            decoders,
+            textures: Mutex::default(),
+        }
+    }
+
+    /// Returns the texture of `source` in `format`, calling `load` if it isn't cached yet.
+    ///
+    /// Other threads requesting the same texture in the meantime wait for `load` to finish.
+    pub fn get_or_load(
+        &self,
+        source: TextureSource,
+        format: Format,
+        load: impl FnOnce() -> Result<Texture, AssetError>,
+    ) -> CachedTexture {
+        let entry = self
+            .textures
+            .lock()
+            .unwrap()
+            .entry((source, format))
+            .or_default()
+            .clone();
+
+        entry
+            .get_or_init(|| load().map(|texture| texture.image).map_err(Arc::new))
+            .clone()
+    }
+
+    pub fn get_solid_color(
+        &self,
+        queue: &Arc<Queue>,
+        color: [u8; 4],
+        format: Format,
+    ) -> Arc<ImageView<ImmutableImage>> {
+        self.get_or_load(TextureSource::SolidColor(color), format, || {
+            Ok(load_solid_color(queue, color, format))
+        })
+        .unwrap()
+    }
+
+    /// Number of distinct textures, including the ones that failed to load.
+    pub fn len(&self) -> usize {
+        self.textures.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level_sizes(width: u32, height: u32) -> Vec<usize> {
+        let rgba = vec![0; (width * height * 4) as usize];
+        generate_mipmaps(width, height, rgba, false)
+            .iter()
+            .map(|level| level.len() / 4)
+            .collect()
+    }
+
+    #[test]
+    fn mipmaps_go_down_to_one_texel() {
+        assert_eq!(level_sizes(1, 1), vec![1]);
+        assert_eq!(level_sizes(16, 16), vec![256, 64, 16, 4, 1]);
+        assert_eq!(level_sizes(8, 2), vec![16, 4, 2, 1]);
+    }
+
+    #[test]
+    fn mipmaps_of_odd_sizes_round_down() {
+        // 5x3, 2x1, 1x1.
+        assert_eq!(level_sizes(5, 3), vec![15, 2, 1]);
+        // 7x1, 3x1, 1x1.
+        assert_eq!(level_sizes(7, 1), vec![7, 3, 1]);
+    }
+
+    #[test]
+    fn mipmaps_of_odd_sizes_weight_in_every_texel() {
+        // All 3 texels of the 3x1 image are averaged, including the last column.
+        let rgba = [[0, 0, 0, 255], [0, 0, 0, 255], [210, 210, 210, 255]].concat();
+        let levels = generate_mipmaps(3, 1, rgba, false);
+        assert_eq!(levels[1], vec![70, 70, 70, 255]);
+
+        // The 2 texels of a 5x1 image cover 2.5 texels each, so they share the middle one.
+        let rgba = [0, 50, 100, 150, 200]
+            .map(|value| [value, value, value, 255])
+            .concat();
+        let levels = generate_mipmaps(5, 1, rgba, false);
+        assert_eq!(levels[1], vec![40, 40, 40, 255, 160, 160, 160, 255]);
+
+        // Every row of a 3x3 image contributes too.
+        let rgba = [vec![0; 6 * 4], vec![90; 3 * 4]].concat();
+        let levels = generate_mipmaps(3, 3, rgba, false);
+        assert_eq!(levels[1], vec![30, 30, 30, 30]);
+    }
+
+    #[test]
+    fn srgb_mipmaps_average_in_linear_space() {
+        let rgba = [[0, 0, 0, 0], [255, 255, 255, 255]].concat();
+
+        let linear = generate_mipmaps(2, 1, rgba.clone(), false);
+        assert_eq!(linear[1], vec![128, 128, 128, 128]);
+
+        // Half of the linear intensity is brighter in sRGB, while alpha is always linear.
+        let srgb = generate_mipmaps(2, 1, rgba, true);
+        assert_eq!(srgb[1], vec![188, 188, 188, 128]);
+    }
+}
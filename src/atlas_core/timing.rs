@@ -0,0 +1,306 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use vulkano::{
+    command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer},
+    device::Queue,
+    query::{QueryPool, QueryPoolCreateInfo, QueryResultFlags, QueryType},
+    sync::PipelineStage,
+};
+
+/// The parts of a frame that are timed on the GPU, in the order they are recorded.
+#[derive(Clone, Copy, PartialEq)]
+pub enum GpuPass {
+    Shadows,
+    Geometry,
+    Lighting,
+    Ui,
+}
+
+impl GpuPass {
+    pub const ALL: [GpuPass; 4] = [
+        GpuPass::Shadows,
+        GpuPass::Geometry,
+        GpuPass::Lighting,
+        GpuPass::Ui,
+    ];
+
+    pub fn get_text(&self) -> &str {
+        match self {
+            GpuPass::Shadows => "Shadows",
+            GpuPass::Geometry => "Geometry",
+            GpuPass::Lighting => "Lighting",
+            GpuPass::Ui => "UI",
+        }
+    }
+}
+
+/// GPU time of every pass of a frame, in milliseconds, indexed by `GpuPass`.
+#[derive(Clone, Copy, Default)]
+pub struct PassTimings {
+    pub passes: [f32; GpuPass::ALL.len()],
+}
+
+impl PassTimings {
+    pub fn get(&self, pass: GpuPass) -> f32 {
+        self.passes[pass as usize]
+    }
+
+    pub fn total(&self) -> f32 {
+        self.passes.iter().sum()
+    }
+}
+
+/// Query pools that are used in turn, so the results of a frame can be read without waiting for
+/// it once the frames that use the other pools have been recorded.
+const TIMER_FRAMES: usize = 4;
+/// One timestamp at the start of the frame, and one at the end of every pass.
+const TIMESTAMPS: u32 = GpuPass::ALL.len() as u32 + 1;
+
+/// Measures how long the GPU takes to execute the passes of a frame with timestamp queries.
+pub struct GpuTimer {
+    pools: Vec<Arc<QueryPool>>,
+    /// Whether the pool has been recorded into since it was created.
+    used: [bool; TIMER_FRAMES],
+    current: usize,
+    /// Nanoseconds per timestamp tick.
+    period: f32,
+    /// The bits of the timestamps that are valid, the others are undefined.
+    mask: u64,
+    /// Timings of the last frame that finished, until they are taken.
+    results: Option<PassTimings>,
+}
+
+impl GpuTimer {
+    /// Returns `None` if the queue doesn't support timestamps.
+    pub fn new(queue: &Arc<Queue>) -> Option<GpuTimer> {
+        let valid_bits = queue.family().timestamp_valid_bits()?;
+
+        let pools = (0..TIMER_FRAMES)
+            .map(|_| {
+                QueryPool::new(
+                    queue.device().clone(),
+                    QueryPoolCreateInfo {
+                        query_count: TIMESTAMPS,
+                        ..QueryPoolCreateInfo::query_type(QueryType::Timestamp)
+                    },
+                )
+                .unwrap()
+            })
+            .collect();
+
+        Some(GpuTimer {
+            pools,
+            used: [false; TIMER_FRAMES],
+            current: 0,
+            period: queue
+                .device()
+                .physical_device()
+                .properties()
+                .timestamp_period,
+            mask: u64::MAX >> (64 - valid_bits.min(64)),
+            results: None,
+        })
+    }
+
+    /// Switches to the next query pool, and keeps the results of the frame that used it last if
+    /// they are available.
+    ///
+    /// Has to be recorded outside of a render pass, before any work of the frame.
+    pub fn begin_frame(
+        &mut self,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+    ) {
+        self.current = (self.current + 1) % TIMER_FRAMES;
+        if self.used[self.current] {
+            if let Some(timings) = self.read(self.current, false) {
+                self.results = Some(timings);
+            }
+        }
+        self.used[self.current] = true;
+
+        let pool = &self.pools[self.current];
+        unsafe {
+            builder
+                .reset_query_pool(pool.clone(), 0..TIMESTAMPS)
+                .unwrap()
+                .write_timestamp(pool.clone(), 0, PipelineStage::TopOfPipe)
+                .unwrap();
+        }
+    }
+
+    /// Marks the end of `pass`, which started where the previous pass ended.
+    pub fn end_pass(
+        &self,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        pass: GpuPass,
+    ) {
+        let query = pass as u32 + 1;
+        unsafe {
+            builder
+                .write_timestamp(
+                    self.pools[self.current].clone(),
+                    query,
+                    PipelineStage::BottomOfPipe,
+                )
+                .unwrap();
+        }
+    }
+
+    /// Returns the timings of the last frame that finished since the previous call.
+    pub fn take_results(&mut self) -> Option<PassTimings> {
+        self.results.take()
+    }
+
+    /// Waits for the current frame to finish and returns its timings, for callers that wait for
+    /// every frame anyway.
+    pub fn read_current(&self) -> Option<PassTimings> {
+        self.read(self.current, true)
+    }
+
+    fn read(&self, pool: usize, wait: bool) -> Option<PassTimings> {
+        let mut timestamps = [0u64; TIMESTAMPS as usize];
+        let flags = QueryResultFlags {
+            wait,
+            ..QueryResultFlags::default()
+        };
+        let available = self.pools[pool]
+            .queries_range(0..TIMESTAMPS)
+            .unwrap()
+            .get_results(&mut timestamps, flags)
+            .ok()?;
+        if !available {
+            return None;
+        }
+
+        let mut timings = PassTimings::default();
+        for (i, pair) in timestamps.windows(2).enumerate() {
+            let ticks = pair[1].wrapping_sub(pair[0]) & self.mask;
+            timings.passes[i] = ticks as f32 * self.period / 1_000_000.0;
+        }
+        Some(timings)
+    }
+}
+
+/// Timings of a single frame, in milliseconds.
+#[derive(Clone, Copy, Default)]
+pub struct FrameTiming {
+    /// Time spent recording and submitting the command buffer.
+    pub cpu_ms: f32,
+    /// `None` if the GPU doesn't support timestamps.
+    pub gpu_ms: Option<f32>,
+    /// Wall clock time from the start of the frame until the GPU finished it.
+    pub frame_ms: f32,
+}
+
+pub struct FrameStats {
+    pub min: f32,
+    pub avg: f32,
+    pub p95: f32,
+    pub p99: f32,
+    pub max: f32,
+}
+
+impl FrameStats {
+    /// Returns `None` if there are no times.
+    pub fn from_times(times: &[f32]) -> Option<FrameStats> {
+        if times.is_empty() {
+            return None;
+        }
+
+        let mut sorted = times.to_vec();
+        sorted.sort_by(f32::total_cmp);
+        // Nearest-rank percentiles.
+        let percentile = |p: f32| {
+            let rank = (p / 100.0 * sorted.len() as f32).ceil() as usize;
+            sorted[rank.clamp(1, sorted.len()) - 1]
+        };
+
+        Some(FrameStats {
+            min: sorted[0],
+            avg: sorted.iter().sum::<f32>() / sorted.len() as f32,
+            p95: percentile(95.0),
+            p99: percentile(99.0),
+            max: sorted[sorted.len() - 1],
+        })
+    }
+}
+
+/// Writes one row per frame.
+pub fn write_frame_times_csv(path: &Path, timings: &[FrameTiming]) -> io::Result<()> {
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "frame,cpu_ms,gpu_ms,frame_ms")?;
+    for (i, timing) in timings.iter().enumerate() {
+        let gpu_ms = timing
+            .gpu_ms
+            .map_or(String::new(), |ms| format!("{:.4}", ms));
+        writeln!(
+            writer,
+            "{},{:.4},{},{:.4}",
+            i, timing.cpu_ms, gpu_ms, timing.frame_ms
+        )?;
+    }
+    writer.flush()
+}
+
+/// Writes one row of statistics per measured quantity.
+pub fn write_stats_csv(path: &Path, stats: &[(&str, FrameStats)]) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "metric,min_ms,avg_ms,p95_ms,p99_ms,max_ms")?;
+    for (name, stats) in stats {
+        writeln!(
+            writer,
+            "{},{:.4},{:.4},{:.4},{:.4},{:.4}",
+            name, stats.min, stats.avg, stats.p95, stats.p99, stats.max
+        )?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn times(count: usize) -> Vec<f32> {
+        // Reversed, so the times have to be sorted first.
+        (1..=count).rev().map(|time| time as f32).collect()
+    }
+
+    #[test]
+    fn no_times_have_no_stats() {
+        assert!(FrameStats::from_times(&[]).is_none());
+    }
+
+    #[test]
+    fn single_time_is_every_statistic() {
+        let stats = FrameStats::from_times(&[4.0]).unwrap();
+        for value in [stats.min, stats.avg, stats.p95, stats.p99, stats.max] {
+            assert_eq!(value, 4.0);
+        }
+    }
+
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        let stats = FrameStats::from_times(&[3.0, 1.0, 4.0, 2.0]).unwrap();
+        assert_eq!((stats.min, stats.avg, stats.max), (1.0, 2.5, 4.0));
+        assert_eq!((stats.p95, stats.p99), (4.0, 4.0));
+
+        // The 95th percentile of 20 times is the 19th.
+        let stats = FrameStats::from_times(&times(20)).unwrap();
+        assert_eq!((stats.p95, stats.p99), (19.0, 20.0));
+
+        let stats = FrameStats::from_times(&times(100)).unwrap();
+        assert_eq!((stats.p95, stats.p99), (95.0, 99.0));
+
+        let stats = FrameStats::from_times(&times(1000)).unwrap();
+        assert_eq!((stats.p95, stats.p99), (950.0, 990.0));
+    }
+}
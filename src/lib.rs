@@ -0,0 +1 @@
+pub mod atlas_core;
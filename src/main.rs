@@ -1,39 +1,253 @@
-use crate::atlas_core::camera::CameraInputLogic;
-use atlas_core::{
-    camera::construct_camera,
+use atlas::atlas_core::{
+    self,
+    bookmarks::{CameraBookmarks, BOOKMARKS_PATH},
+    camera::{construct_camera, get_controller, Camera, ControllerType, UiFocus},
+    capture::{record_capture, screenshot_path},
     egui::{get_egui_context, render_egui, update_textures_egui, FrameEndFuture},
+    loader::{AssetLoader, LoadProgress},
     mesh::load_gltf,
-    renderer::{
-        deferred::{self, deferred_vert_mod, get_lighting_uniform_buffer},
-        triangle_draw_system::TriangleDrawSystem,
-    },
+    renderer::deferred::DeferredRenderer,
+    scene::{CullingStats, Scene},
+    texture::TextureCache,
+    timing::{write_frame_times_csv, write_stats_csv, FrameStats, FrameTiming, GpuPass},
     PerformanceInfo,
 };
-use cgmath::Matrix4;
+use cgmath::{Matrix4, Vector3};
 
-use std::{path::Path, time::Instant};
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
 use vulkano::{
-    buffer::{BufferUsage, CpuAccessibleBuffer, CpuBufferPool},
-    command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, SubpassContents},
-    descriptor_set::{PersistentDescriptorSet, WriteDescriptorSet},
-    pipeline::{graphics::viewport::Viewport, Pipeline, PipelineBindPoint},
+    command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage},
+    device::Queue,
+    pipeline::graphics::viewport::Viewport,
     swapchain::{acquire_next_image, AcquireError, SwapchainCreateInfo, SwapchainCreationError},
     sync::{FlushError, GpuFuture},
 };
 use winit::{
-    event::{Event, WindowEvent},
+    event::{Event, VirtualKeyCode, WindowEvent},
     event_loop::ControlFlow,
 };
 use winit_input_helper::WinitInputHelper;
 
-mod atlas_core;
+/// Frames rendered by `--benchmark`, unless `--frames` is passed.
+const BENCHMARK_FRAMES: usize = 1000;
+/// Frames rendered before measuring, which aren't part of the results.
+const BENCHMARK_WARMUP_FRAMES: usize = 30;
+/// The camera path through Sponza that `--benchmark` follows, so results are comparable between
+/// machines and changes.
+const BENCHMARK_PATH: &str = "assets/benchmark_path.ron";
+
+/// Longest frame time the camera moves by, in seconds, so a stall like loading a model doesn't
+/// send it flying.
+const MAX_DELTA_TIME: f32 = 0.1;
+
+const BOOKMARK_KEYS: [VirtualKeyCode; 9] = [
+    VirtualKeyCode::Key1,
+    VirtualKeyCode::Key2,
+    VirtualKeyCode::Key3,
+    VirtualKeyCode::Key4,
+    VirtualKeyCode::Key5,
+    VirtualKeyCode::Key6,
+    VirtualKeyCode::Key7,
+    VirtualKeyCode::Key8,
+    VirtualKeyCode::Key9,
+];
+
+/// Sponza, followed by the paths passed as `--model <path>`.
+fn model_paths() -> Vec<String> {
+    let args: Vec<String> = std::env::args().collect();
+    let extra_paths = args
+        .windows(2)
+        .filter(|pair| pair[0] == "--model")
+        .map(|pair| pair[1].clone());
+
+    std::iter::once("assets/models/sponza/sponza.glb".to_owned())
+        .chain(extra_paths)
+        .collect()
+}
+
+/// Adds `model` to the scene, followed by the copies requested with `--instances <count>` in a
+/// row along the X axis.
+fn add_model(scene: &mut Scene, model: Scene) {
+    let instances: usize = get_arg_value("--instances").map_or(1, |instances| {
+        instances.parse().expect("--instances should be a number")
+    });
+
+    let root = scene.root;
+    let model = scene.add_scene(model, root);
+    // We need to turn the model upside-down.
+    let flip = Matrix4::from_nonuniform_scale(1.0, -1.0, 1.0);
+    scene.nodes[model].transform = flip;
+
+    if instances < 2 {
+        return;
+    }
+    scene.update_transforms();
+    let spacing = scene
+        .get_bounds(model)
+        .map_or(0.0, |bounds| (bounds.max.x - bounds.min.x) * 1.1);
+    for i in 1..instances {
+        let instance = scene.instantiate(model, root);
+        let offset = Vector3::new(spacing * i as f32, 0.0, 0.0);
+        scene.nodes[instance].transform = Matrix4::from_translation(offset) * flip;
+    }
+}
+
+/// Loads all models on the current thread, skipping models that fail to load.
+fn load_scene(queue: &Arc<Queue>) -> Scene {
+    let mut scene = Scene::new();
+    let mut errors = vec![];
+    let progress = LoadProgress::default();
+    let cache = TextureCache::default();
+
+    for path in model_paths() {
+        match load_gltf(queue, Path::new(&path), &mut errors, &progress, &cache) {
+            Ok(model) => add_model(&mut scene, model),
+            Err(error) => errors.push(error),
+        }
+    }
+
+    for error in errors {
+        eprintln!("{}", error);
+    }
+
+    scene
+}
+
+fn run_headless() {
+    let system = atlas_core::init_headless([1920, 1080]);
+    let mut renderer = DeferredRenderer::new(&system.queue, &system.render_pass);
+    let mut scene = load_scene(&system.queue);
+
+    let mut camera = construct_camera();
+    system.render_frame(&mut renderer, &mut camera, &mut scene);
+
+    let path = screenshot_path();
+    system
+        .capture()
+        .save_png(&path)
+        .expect("Could not save headless frame");
+
+    println!(
+        "Rendered a headless frame on {} to {}",
+        system.info.device_name,
+        path.display()
+    );
+}
+
+/// The value after the first `name` argument.
+fn get_arg_value(name: &str) -> Option<String> {
+    let args: Vec<String> = std::env::args().collect();
+    args.windows(2)
+        .find(|pair| pair[0] == name)
+        .map(|pair| pair[1].clone())
+}
+
+/// Renders `--frames` frames offscreen while moving the camera along a camera path, and writes
+/// the frame times and their statistics to the `benchmarks` directory.
+fn run_benchmark() {
+    let frames: usize = get_arg_value("--frames").map_or(BENCHMARK_FRAMES, |frames| {
+        frames.parse().expect("--frames should be a number")
+    });
+
+    let system = atlas_core::init_headless([1920, 1080]);
+    let mut renderer = DeferredRenderer::new(&system.queue, &system.render_pass);
+    let mut scene = load_scene(&system.queue);
+
+    // A path recorded into the bookmarks with `--path`, or else the reference path.
+    let (file, name) = match get_arg_value("--path") {
+        Some(name) => (BOOKMARKS_PATH, Some(name)),
+        None => (BENCHMARK_PATH, None),
+    };
+    let bookmarks = CameraBookmarks::load(Path::new(file));
+    let path = match &name {
+        Some(name) => bookmarks.file.paths.iter().find(|path| &path.name == name),
+        None => bookmarks.file.paths.first(),
+    }
+    .unwrap_or_else(|| panic!("No camera path {} in {}", name.unwrap_or_default(), file));
+    println!(
+        "Benchmarking {} frames along camera path {} from {}",
+        frames, path.name, file
+    );
+
+    let mut camera = construct_camera();
+    // The path is sampled by frame instead of by time, so every run renders the same frames.
+    let set_frame = |camera: &mut Camera, frame: usize| {
+        let t = frame as f32 / (frames.max(2) - 1) as f32;
+        if let Some(pose) = path.get_pose(t * path.duration()) {
+            pose.apply(camera);
+        }
+    };
+
+    // Lets the driver finish compiling pipelines and uploading resources.
+    for frame in 0..BENCHMARK_WARMUP_FRAMES.min(frames) {
+        set_frame(&mut camera, frame);
+        system.render_frame(&mut renderer, &mut camera, &mut scene);
+    }
+
+    let timings: Vec<FrameTiming> = (0..frames)
+        .map(|frame| {
+            set_frame(&mut camera, frame);
+            system.render_frame(&mut renderer, &mut camera, &mut scene)
+        })
+        .collect();
+
+    let mut stats = vec![];
+    let metrics: [(&str, fn(&FrameTiming) -> Option<f32>); 3] = [
+        ("cpu", |timing| Some(timing.cpu_ms)),
+        ("gpu", |timing| timing.gpu_ms),
+        ("frame", |timing| Some(timing.frame_ms)),
+    ];
+    for (name, get) in metrics {
+        let times: Vec<f32> = timings.iter().filter_map(get).collect();
+        if let Some(metric_stats) = FrameStats::from_times(&times) {
+            stats.push((name, metric_stats));
+        }
+    }
+
+    println!("Device: {}", system.info.device_name);
+    println!(
+        "{:<6} {:>9} {:>9} {:>9} {:>9} {:>9}",
+        "ms", "min", "avg", "p95", "p99", "max"
+    );
+    for (name, stats) in &stats {
+        println!(
+            "{:<6} {:>9.3} {:>9.3} {:>9.3} {:>9.3} {:>9.3}",
+            name, stats.min, stats.avg, stats.p95, stats.p99, stats.max
+        );
+    }
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    let times_path = PathBuf::from(format!("benchmarks/atlas_{}.csv", timestamp));
+    let stats_path = PathBuf::from(format!("benchmarks/atlas_{}_summary.csv", timestamp));
+    write_frame_times_csv(&times_path, &timings).expect("Could not write frame times");
+    write_stats_csv(&stats_path, &stats).expect("Could not write frame time statistics");
+
+    println!(
+        "Wrote the frame times to {} and {}",
+        times_path.display(),
+        stats_path.display()
+    );
+}
 
 fn main() {
+    if std::env::args().any(|arg| arg == "--headless") {
+        run_headless();
+        return;
+    }
+    if std::env::args().any(|arg| arg == "--benchmark") {
+        run_benchmark();
+        return;
+    }
+
     let mut system = atlas_core::init("Atlas Engine");
-    let uniform_buffer = CpuBufferPool::<deferred_vert_mod::ty::CameraData>::new(
-        system.device.clone(),
-        BufferUsage::all(),
-    );
 
     let mut viewport = Viewport {
         origin: [0.0, 0.0],
@@ -41,13 +255,12 @@ fn main() {
         depth_range: 0.0..1.0,
     };
 
-    let (mut framebuffers, mut color_buffer, mut normal_buffer, mut position_buffer) =
-        atlas_core::window_size_dependent_setup(
-            system.device.clone(),
-            &system.images,
-            system.render_pass.render_pass.clone(),
-            &mut viewport,
-        );
+    let mut gbuffer = atlas_core::window_size_dependent_setup(
+        system.device.clone(),
+        &system.images,
+        system.render_pass.render_pass.clone(),
+        &mut viewport,
+    );
 
     let mut recreate_swapchain = false;
     let mut previous_frame_end = Some(FrameEndFuture::now(system.device.clone()));
@@ -56,7 +269,10 @@ fn main() {
         get_egui_context(&system, &system.render_pass.render_pass);
 
     let mut camera = construct_camera();
+    let mut controller = get_controller(ControllerType::Fly, &camera);
+    let mut bookmarks = CameraBookmarks::load(Path::new(BOOKMARKS_PATH));
     let mut input = WinitInputHelper::new();
+    let mut capture_requested = false;
 
     let game_start = Instant::now();
     let mut last_update = Instant::now();
@@ -64,25 +280,37 @@ fn main() {
     let mut performance_info = PerformanceInfo {
         game_start,
         delta_time_ms: 0.0,
+        culling: CullingStats::default(),
+        gpu_timings: VecDeque::new(),
     };
 
-    let (deferred_pipeline, lighting_pipeline) =
-        deferred::init_pipelines(&system.device, &system.render_pass);
-
-    let triangle_system = TriangleDrawSystem::new(&system.queue);
-
-    let layout = deferred_pipeline.layout().set_layouts().get(1).unwrap();
-    let mut mesh = load_gltf(
-        &system,
-        layout,
-        Path::new("assets/models/sponza/sponza.glb"),
-    );
-    // We need to turn the model upside-down.
-    mesh.model_matrix = Matrix4::from_nonuniform_scale(1.0, -1.0, 1.0);
+    let mut renderer = DeferredRenderer::new(&system.queue, &system.render_pass);
+    let mut scene = Scene::new();
+    let mut loader = AssetLoader::new();
+    for path in model_paths() {
+        loader.load(&system.transfer_queue, &path);
+    }
 
     system.event_loop.run(move |event, _, control_flow| {
         if input.update(&event) {
-            camera.handle_event(&input);
+            if input.key_pressed(VirtualKeyCode::F12) {
+                capture_requested = true;
+            }
+
+            // The number keys recall bookmarks, or store the current view with control held.
+            // Ignored while typing in the UI, e.g. when renaming a bookmark.
+            if !egui_ctx.wants_keyboard_input() {
+                for (i, &key) in BOOKMARK_KEYS.iter().enumerate() {
+                    if !input.key_pressed(key) {
+                        continue;
+                    }
+                    if input.held_control() {
+                        bookmarks.set_bookmark(i, &camera);
+                    } else {
+                        bookmarks.go_to(i, &mut camera, &mut controller);
+                    }
+                }
+            }
         }
 
         match event {
@@ -120,72 +348,38 @@ fn main() {
                         };
 
                     system.swapchain = new_swapchain;
-                    let (
-                        new_framebuffers,
-                        new_color_buffer,
-                        new_normal_buffer,
-                        new_position_buffer,
-                    ) = atlas_core::window_size_dependent_setup(
+                    system.images = new_images;
+                    gbuffer = atlas_core::window_size_dependent_setup(
                         system.device.clone(),
-                        &new_images,
+                        &system.images,
                         system.render_pass.render_pass.clone(),
                         &mut viewport,
                     );
-
-                    framebuffers = new_framebuffers;
-                    color_buffer = new_color_buffer;
-                    normal_buffer = new_normal_buffer;
-                    position_buffer = new_position_buffer;
                     recreate_swapchain = false;
                 }
 
-                let uniform_buffer_subbuffer = {
-                    performance_info.delta_time_ms =
-                        (Instant::now() - last_update).as_secs_f32() * 1000.0;
-                    last_update = Instant::now();
-
-                    let extent = system.swapchain.image_extent();
-                    camera.aspect_ratio = extent[0] as f32 / extent[1] as f32;
-                    camera.world = mesh.model_matrix.into();
-                    camera.update();
-
-                    let uniform_data = deferred_vert_mod::ty::CameraData {
-                        world_view: camera.world_view.into(),
-                        world: camera.world.into(),
-                        view: camera.view.into(),
-                        proj: camera.proj.into(),
+                performance_info.delta_time_ms =
+                    (Instant::now() - last_update).as_secs_f32() * 1000.0;
+                last_update = Instant::now();
+                // Once per frame, so the camera moves at the same speed at any frame rate.
+                let delta_time = (performance_info.delta_time_ms / 1000.0).min(MAX_DELTA_TIME);
+                if !bookmarks.update(&mut camera, &mut controller, delta_time) {
+                    // Scrolling, dragging or typing in the UI doesn't move the camera.
+                    let focus = UiFocus {
+                        pointer: egui_ctx.wants_pointer_input(),
+                        keyboard: egui_ctx.wants_keyboard_input(),
                     };
+                    controller.handle_input(&mut camera, &input, focus, delta_time);
+                }
 
-                    uniform_buffer.next(uniform_data).unwrap()
-                };
+                let extent = system.swapchain.image_extent();
+                camera.aspect_ratio = extent[0] as f32 / extent[1] as f32;
 
-                let deferred_layout = deferred_pipeline.layout().set_layouts().get(0).unwrap();
-                let deferred_set = PersistentDescriptorSet::new(
-                    deferred_layout.clone(),
-                    [WriteDescriptorSet::buffer(
-                        0,
-                        uniform_buffer_subbuffer.clone(),
-                    )],
-                )
-                .unwrap();
-
-                let lighting_layout = lighting_pipeline.layout().set_layouts().get(0).unwrap();
-                let lighting_set = PersistentDescriptorSet::new(
-                    lighting_layout.clone(),
-                    [
-                        WriteDescriptorSet::image_view(0, color_buffer.clone()),
-                        WriteDescriptorSet::image_view(1, normal_buffer.clone()),
-                        WriteDescriptorSet::image_view(2, position_buffer.clone()),
-                        WriteDescriptorSet::buffer(
-                            3,
-                            get_lighting_uniform_buffer(
-                                &system.device.clone(),
-                                &system.render_pass.params,
-                            ),
-                        ),
-                    ],
-                )
-                .unwrap();
+                // The renderer merges the new models into the buffers of the scene.
+                for model in loader.poll() {
+                    add_model(&mut scene, model);
+                }
+                scene.update_transforms();
 
                 let (image_num, suboptimal, acquire_future) =
                     match acquire_next_image(system.swapchain.clone(), None) {
@@ -217,41 +411,33 @@ fn main() {
                     &mut egui_painter,
                     &mut egui_winit,
                     &mut system.render_pass.params,
+                    &mut camera,
+                    &mut controller,
+                    &scene,
+                    &mut bookmarks,
+                    &loader,
                 );
+                // After the UI, which can change the projection.
+                camera.update();
 
-                let clear_values = vec![
-                    [0.0, 0.0, 0.0, 1.0].into(),
-                    [0.0, 0.0, 0.0, 1.0].into(),
-                    [0.0, 0.0, 0.0, 1.0].into(),
-                    [0.0, 0.0, 0.0, 1.0].into(),
-                    1f32.into(),
-                ];
-
-                builder
-                    .begin_render_pass(
-                        framebuffers[image_num].clone(),
-                        SubpassContents::Inline,
-                        clear_values,
-                    )
-                    .unwrap()
-                    .set_viewport(0, [viewport.clone()])
-                    .bind_pipeline_graphics(deferred_pipeline.clone());
-
-                mesh.render(&mut builder, &deferred_pipeline, &deferred_set);
-
-                builder
-                    .next_subpass(SubpassContents::Inline)
-                    .unwrap()
-                    .bind_pipeline_graphics(lighting_pipeline.clone())
-                    .bind_descriptor_sets(
-                        PipelineBindPoint::Graphics,
-                        lighting_pipeline.layout().clone(),
-                        0,
-                        lighting_set.clone(),
-                    )
-                    .bind_vertex_buffers(0, triangle_system.vertex_buffer.clone())
-                    .draw(6, 1, 0, 0)
-                    .unwrap();
+                renderer.draw(
+                    &mut builder,
+                    gbuffer.framebuffers[image_num].clone(),
+                    &gbuffer,
+                    &viewport,
+                    &camera,
+                    &mut scene,
+                    &system.render_pass.params,
+                );
+                // Shown in the next frame, as the UI is built before the scene is drawn.
+                performance_info.culling = renderer.culling;
+                if let Some(timings) = renderer
+                    .gpu_timer
+                    .as_mut()
+                    .and_then(|timer| timer.take_results())
+                {
+                    performance_info.push_gpu_timings(timings);
+                }
 
                 render_egui(
                     &mut builder,
@@ -260,9 +446,21 @@ fn main() {
                     shapes,
                     &mut egui_painter,
                 );
+                renderer.end_pass(&mut builder, GpuPass::Ui);
 
                 builder.end_render_pass().unwrap();
 
+                let capture = if capture_requested {
+                    capture_requested = false;
+                    Some(record_capture(
+                        &system.device,
+                        &mut builder,
+                        system.images[image_num].clone(),
+                    ))
+                } else {
+                    None
+                };
+
                 let command_buffer = builder.build().unwrap();
 
                 if wait_for_last_frame {
@@ -287,6 +485,16 @@ fn main() {
 
                 match future {
                     Ok(future) => {
+                        if let Some(capture) = capture {
+                            future.wait(None).unwrap();
+
+                            let path = screenshot_path();
+                            match capture.save_png(&path) {
+                                Ok(()) => println!("Saved screenshot to {}", path.display()),
+                                Err(e) => println!("Failed to save screenshot: {:?}", e),
+                            }
+                        }
+
                         previous_frame_end = Some(FrameEndFuture::FenceSignalFuture(future));
                     }
                     Err(FlushError::OutOfDate) => {
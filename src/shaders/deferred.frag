@@ -1,18 +1,82 @@
 #version 450
+#extension GL_EXT_nonuniform_qualifier : require
+
 layout(location = 0) in vec3 in_normal;
 layout(location = 1) in vec2 tex_coords;
+layout(location = 2) in vec3 in_position;
+layout(location = 3) in vec4 in_tangent;
+layout(location = 4) flat in uint in_material;
 
 layout(location = 0) out vec4 f_color;
 layout(location = 1) out vec4 f_normal;
+layout(location = 2) out vec4 f_position;
+layout(location = 3) out vec4 f_emissive;
+
+struct MaterialData {
+    vec4 base_color_factor;
+    vec3 emissive_factor;
+    // Indices into `textures`. This one fills the padding after `emissive_factor`.
+    uint normal_texture;
+    float metallic_factor;
+    float roughness_factor;
+    float occlusion_strength;
+    float normal_scale;
+    uint base_color_texture;
+    // Roughness in the green channel and metallic in the blue channel, as defined by glTF.
+    uint metallic_roughness_texture;
+    uint occlusion_texture;
+    uint emissive_texture;
+};
+
+layout(set = 1, binding = 0) readonly buffer Materials {
+    MaterialData materials[];
+};
+
+// The textures of all materials in the scene.
+layout(set = 1, binding = 1) uniform sampler2D textures[];
+
+MaterialData material;
+
+vec4 sample_texture(uint index) {
+    return texture(textures[nonuniformEXT(index)], tex_coords);
+}
+
+vec3 get_normal() {
+    vec3 normal = normalize(in_normal);
 
-layout(set = 1, binding = 0) uniform sampler2D tex;
+    // Meshes without tangents keep their vertex normals.
+    if (dot(in_tangent.xyz, in_tangent.xyz) == 0.0) {
+        return normal;
+    }
+
+    // Re-orthogonalize, as the interpolated tangent and normal are no longer perpendicular.
+    vec3 tangent = normalize(in_tangent.xyz - normal * dot(normal, in_tangent.xyz));
+    vec3 bitangent = cross(normal, tangent) * in_tangent.w;
+
+    vec3 tangent_normal = sample_texture(material.normal_texture).xyz * 2.0 - 1.0;
+    tangent_normal.xy *= material.normal_scale;
+
+    return normalize(mat3(tangent, bitangent, normal) * tangent_normal);
+}
 
 void main() {
-    vec4 tex_color = texture(tex, tex_coords);
-    if (tex_color.a < 0.01)
+    material = materials[in_material];
+
+    vec4 base_color = sample_texture(material.base_color_texture) * material.base_color_factor;
+    if (base_color.a < 0.01)
         discard;
 
-    vec3 regular_color = tex_color.rgb + vec3(0.0);
-    f_color = vec4(regular_color, 1.0);
-    f_normal = vec4(in_normal, 1.0);
+    vec4 metallic_roughness = sample_texture(material.metallic_roughness_texture);
+    float metallic = metallic_roughness.b * material.metallic_factor;
+    float roughness = metallic_roughness.g * material.roughness_factor;
+
+    float occlusion = sample_texture(material.occlusion_texture).r;
+    occlusion = mix(1.0, occlusion, material.occlusion_strength);
+
+    vec3 emissive = sample_texture(material.emissive_texture).rgb * material.emissive_factor;
+
+    f_color = vec4(base_color.rgb, 1.0);
+    f_normal = vec4(get_normal(), metallic);
+    f_position = vec4(in_position, roughness);
+    f_emissive = vec4(emissive, occlusion);
 }
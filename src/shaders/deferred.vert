@@ -3,19 +3,38 @@
 layout(location = 0) in vec3 position;
 layout(location = 1) in vec3 normal;
 layout(location = 2) in vec2 tex_coord;
+layout(location = 3) in vec4 tangent;
 
 layout(location = 0) out vec3 v_normal;
 layout(location = 1) out vec2 out_coords;
+layout(location = 2) out vec3 v_position;
+layout(location = 3) out vec4 v_tangent;
+layout(location = 4) flat out uint v_material;
 
 layout(set = 0, binding = 0) uniform CameraData {
-    mat4 world_view;
-    mat4 world;
     mat4 view;
     mat4 proj;
 } uniforms;
 
+// Indexed by the first instance of each indirect draw.
+layout(set = 0, binding = 1) readonly buffer DrawTransforms {
+    mat4 transforms[];
+};
+layout(set = 0, binding = 2) readonly buffer DrawMaterials {
+    uint materials[];
+};
+
 void main() {
+    mat4 model = transforms[gl_InstanceIndex];
+    vec4 world_position = model * vec4(position, 1.0);
+
     out_coords = tex_coord;
-    v_normal = mat3(uniforms.world) * normal;
-    gl_Position = uniforms.proj * uniforms.world_view * vec4(position, 1.0);
+    v_normal = transpose(inverse(mat3(model))) * normal;
+    v_position = world_position.xyz;
+    v_material = materials[gl_InstanceIndex];
+
+    // Mirroring transforms, like the flipped y axis of Sponza, swap the handedness of the tangents.
+    float handedness = determinant(mat3(model)) < 0.0 ? -1.0 : 1.0;
+    v_tangent = vec4(mat3(model) * tangent.xyz, tangent.w * handedness);
+    gl_Position = uniforms.proj * uniforms.view * world_position;
 }
@@ -3,6 +3,7 @@
 layout(input_attachment_index = 0, set = 0, binding = 0) uniform subpassInput u_color;
 layout(input_attachment_index = 1, set = 0, binding = 1) uniform subpassInput u_normal;
 layout(input_attachment_index = 2, set = 0, binding = 2) uniform subpassInput u_position;
+layout(input_attachment_index = 3, set = 0, binding = 6) uniform subpassInput u_emissive;
 
 layout(location = 0) out vec4 f_color;
 
@@ -10,20 +11,173 @@ layout(set = 0, binding = 3) uniform LightingData {
     vec4 ambient_color;
     vec4 directional_direction;
     vec4 directional_color;
+    vec4 camera_position;
+    mat4 view;
+    mat4 cascade_matrices[4];
+    vec4 cascade_splits;
     int preview_type;
+    int light_count;
+    int cascade_count;
+    float shadow_bias;
 } u_lighting;
 
-vec3 main_pass(vec3 albedo, vec3 normal, vec3 position) {
+struct Light {
+    vec3 position;
+    float range;
+    vec3 color;
+    float intensity;
+    vec3 direction;
+    int light_type;
+    float cos_inner_cone;
+    float cos_outer_cone;
+};
+
+layout(set = 0, binding = 4) readonly buffer Lights {
+    Light lights[];
+} u_lights;
+
+layout(set = 0, binding = 5) uniform sampler2DArrayShadow u_shadow_map;
+
+// Returns how much of the directional light reaches `position`, using 3x3 PCF.
+float directional_shadow(vec3 position, vec3 normal, vec3 light_direction) {
+    float depth = -(u_lighting.view * vec4(position, 1.0)).z;
+
+    int cascade = -1;
+    for (int i = 0; i < u_lighting.cascade_count; i++) {
+        if (depth < u_lighting.cascade_splits[i]) {
+            cascade = i;
+            break;
+        }
+    }
+
+    // Beyond the shadow distance.
+    if (cascade < 0) {
+        return 1.0;
+    }
+
+    vec4 shadow_position = u_lighting.cascade_matrices[cascade] * vec4(position, 1.0);
+    vec3 coords = shadow_position.xyz / shadow_position.w;
+    vec2 uv = coords.xy * 0.5 + 0.5;
+
+    // Surfaces at grazing angles need a larger bias.
+    float bias = u_lighting.shadow_bias * max(1.0 - dot(normal, light_direction), 0.1);
+    vec2 texel_size = 1.0 / vec2(textureSize(u_shadow_map, 0).xy);
+
+    float visibility = 0.0;
+    for (int x = -1; x <= 1; x++) {
+        for (int y = -1; y <= 1; y++) {
+            vec2 offset = vec2(x, y) * texel_size;
+            visibility += texture(u_shadow_map, vec4(uv + offset, cascade, coords.z - bias));
+        }
+    }
+
+    return visibility / 9.0;
+}
+
+const float PI = 3.14159265359;
+
+struct Surface {
+    vec3 albedo;
+    vec3 normal;
+    vec3 position;
+    vec3 view_direction;
+    float metallic;
+    float roughness;
+};
+
+// Trowbridge-Reitz GGX normal distribution.
+float distribution_ggx(float n_dot_h, float roughness) {
+    float a = roughness * roughness;
+    float a2 = a * a;
+    float d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / max(PI * d * d, 0.0001);
+}
+
+// Smith's method with the Schlick-GGX approximation for direct lighting.
+float geometry_smith(float n_dot_v, float n_dot_l, float roughness) {
+    float r = roughness + 1.0;
+    float k = r * r / 8.0;
+    float g_v = n_dot_v / (n_dot_v * (1.0 - k) + k);
+    float g_l = n_dot_l / (n_dot_l * (1.0 - k) + k);
+    return g_v * g_l;
+}
+
+vec3 fresnel_schlick(float cos_theta, vec3 f0) {
+    return f0 + (1.0 - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+// Cook-Torrance BRDF, returns the light reflected towards the camera.
+vec3 brdf(Surface surface, vec3 light_direction, vec3 radiance) {
+    vec3 n = surface.normal;
+    vec3 v = surface.view_direction;
+    vec3 l = light_direction;
+    vec3 h = normalize(v + l);
+
+    float n_dot_l = max(dot(n, l), 0.0);
+    float n_dot_v = max(dot(n, v), 0.0001);
+    float n_dot_h = max(dot(n, h), 0.0);
+
+    // Dielectrics reflect about 4% at normal incidence, metals reflect their base color.
+    vec3 f0 = mix(vec3(0.04), surface.albedo, surface.metallic);
+    vec3 f = fresnel_schlick(max(dot(h, v), 0.0), f0);
+    float d = distribution_ggx(n_dot_h, surface.roughness);
+    float g = geometry_smith(n_dot_v, n_dot_l, surface.roughness);
+
+    vec3 specular = d * g * f / max(4.0 * n_dot_v * n_dot_l, 0.0001);
+    vec3 diffuse = (1.0 - f) * (1.0 - surface.metallic) * surface.albedo / PI;
+
+    // Light colors are scaled by PI, so a white diffuse surface facing a light reflects exactly the
+    // color of the light, like before the BRDF was introduced.
+    return (diffuse + specular) * radiance * PI * n_dot_l;
+}
+
+vec3 light_pass(Light light, Surface surface) {
+    vec3 to_light = light.position - surface.position;
+    float distance = max(length(to_light), 0.0001);
+    vec3 light_direction = to_light / distance;
+
+    // Smoothly fade out the light towards the edge of its range.
+    float falloff = clamp(1.0 - pow(distance / light.range, 2.0), 0.0, 1.0);
+    float attenuation = falloff * falloff;
+
+    if (light.light_type == 1) {
+        float cos_angle = dot(-light_direction, normalize(light.direction));
+        // smoothstep is undefined when the cones are equal, which gives the cone a hard edge.
+        if (light.cos_inner_cone > light.cos_outer_cone) {
+            attenuation *= smoothstep(light.cos_outer_cone, light.cos_inner_cone, cos_angle);
+        } else {
+            attenuation *= step(light.cos_outer_cone, cos_angle);
+        }
+    }
+
+    return brdf(surface, light_direction, light.color * light.intensity * attenuation);
+}
+
+vec3 main_pass(Surface surface, vec3 emissive, float occlusion) {
     vec3 ambient_color = u_lighting.ambient_color.a * u_lighting.ambient_color.rgb;
-    float directional_intensity = max(dot(normal, u_lighting.directional_direction.xyz), 0.0);
-    vec3 directional_color = directional_intensity * u_lighting.directional_color.xyz;
-    return (ambient_color + directional_color) * albedo;
+    ambient_color *= surface.albedo * occlusion;
+
+    vec3 directional_direction = normalize(u_lighting.directional_direction.xyz);
+    vec3 directional_color = brdf(surface, directional_direction, u_lighting.directional_color.rgb);
+    directional_color *=
+        directional_shadow(surface.position, surface.normal, directional_direction);
+
+    vec3 light_color = 0.0.xxx;
+    for (int i = 0; i < u_lighting.light_count; i++) {
+        light_color += light_pass(u_lights.lights[i], surface);
+    }
+
+    return ambient_color + directional_color + light_color + emissive;
 }
 
 void main() {
     vec3 albedo = subpassLoad(u_color).rgb;
-    vec3 normal = subpassLoad(u_normal).rgb;
-    vec3 position = subpassLoad(u_position).rgb;
+    vec4 normal_metallic = subpassLoad(u_normal);
+    vec4 position_roughness = subpassLoad(u_position);
+    vec4 emissive_occlusion = subpassLoad(u_emissive);
+
+    vec3 normal = normal_metallic.xyz;
+    vec3 position = position_roughness.xyz;
 
     vec3 final_output = 0.0.xxx;
 
@@ -34,7 +188,16 @@ void main() {
     } else if (u_lighting.preview_type == 3) {
         final_output = position;
     } else {
-        final_output = main_pass(albedo, normal, position);
+        Surface surface;
+        surface.albedo = albedo;
+        surface.normal = normalize(normal);
+        surface.position = position;
+        surface.view_direction = normalize(u_lighting.camera_position.xyz - position);
+        surface.metallic = normal_metallic.w;
+        // The GGX distribution degenerates for perfectly smooth surfaces.
+        surface.roughness = max(position_roughness.w, 0.04);
+
+        final_output = main_pass(surface, emissive_occlusion.rgb, emissive_occlusion.a);
     }
 
     f_color = vec4(final_output, 1.0);
@@ -0,0 +1,16 @@
+#version 450
+
+layout(location = 0) in vec3 position;
+
+layout(push_constant) uniform PushConstants {
+    mat4 view_proj;
+} push;
+
+// Indexed by the first instance of each indirect draw.
+layout(set = 0, binding = 0) readonly buffer DrawTransforms {
+    mat4 transforms[];
+};
+
+void main() {
+    gl_Position = push.view_proj * transforms[gl_InstanceIndex] * vec4(position, 1.0);
+}
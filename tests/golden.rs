@@ -0,0 +1,158 @@
+//! Golden-image regression tests for the deferred renderer.
+//!
+//! Every `DebugPreviewBuffer` mode renders `monkey.glb` offscreen and compares the result against
+//! a reference image in `tests/golden`. Run with `ATLAS_UPDATE_GOLDEN=1` to (re)generate the
+//! reference images after an intentional change to the output of the renderer.
+
+use std::{
+    env,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use atlas::atlas_core::{
+    self,
+    camera::construct_camera,
+    capture::write_png,
+    loader::LoadProgress,
+    mesh::load_gltf,
+    renderer::deferred::{DebugPreviewBuffer, DeferredRenderer},
+    texture::TextureCache,
+};
+use cgmath::{Matrix4, Point3, Vector3};
+
+const DIMENSIONS: [u32; 2] = [256, 256];
+
+/// Maximum difference per color channel before a pixel counts as mismatched.
+const CHANNEL_TOLERANCE: u8 = 3;
+
+/// Fraction of pixels that may mismatch, to absorb rasterization differences between drivers.
+const MAX_MISMATCH_RATIO: f32 = 0.001;
+
+fn render(preview_buffer: DebugPreviewBuffer) -> Vec<u8> {
+    let mut system = atlas_core::init_headless(DIMENSIONS);
+    system.render_pass.params.preview_buffer = preview_buffer;
+
+    let mut renderer = DeferredRenderer::new(&system.queue, &system.render_pass);
+
+    let mut errors = vec![];
+    let mut scene = load_gltf(
+        &system.queue,
+        Path::new("assets/models/monkey.glb"),
+        &mut errors,
+        &LoadProgress::default(),
+        &TextureCache::default(),
+    )
+    .unwrap();
+    assert!(
+        errors.is_empty(),
+        "Errors while loading monkey.glb: {:?}",
+        errors
+    );
+    let root = scene.root;
+    scene.nodes[root].transform = Matrix4::from_nonuniform_scale(3.0, -3.0, 3.0);
+
+    let mut camera = construct_camera();
+    camera.position = Point3::new(0.0, 0.0, 10.0);
+    camera.forward = Vector3::new(0.0, 0.0, -1.0);
+    camera.right = camera.forward.cross(camera.up);
+
+    system.render_frame(&mut renderer, &mut camera, &mut scene);
+    system.capture().to_rgba()
+}
+
+fn read_png(path: &Path) -> Vec<u8> {
+    let decoder = png::Decoder::new(File::open(path).unwrap());
+    let mut reader = decoder.read_info().unwrap();
+    let mut data = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut data).unwrap();
+
+    assert_eq!(
+        (reader.info().width, reader.info().height),
+        (DIMENSIONS[0], DIMENSIONS[1]),
+        "Reference image {} has the wrong size",
+        path.display()
+    );
+    data
+}
+
+/// Marks mismatched pixels red on top of a darkened copy of the actual output.
+fn diff_image(expected: &[u8], actual: &[u8]) -> (Vec<u8>, usize) {
+    let mut mismatches = 0;
+    let diff = expected
+        .chunks(4)
+        .zip(actual.chunks(4))
+        .flat_map(|(e, a)| {
+            let mismatch = e
+                .iter()
+                .zip(a)
+                .any(|(e, a)| e.abs_diff(*a) > CHANNEL_TOLERANCE);
+
+            if mismatch {
+                mismatches += 1;
+                [255, 0, 0, 255]
+            } else {
+                [a[0] / 4, a[1] / 4, a[2] / 4, 255]
+            }
+        })
+        .collect();
+
+    (diff, mismatches)
+}
+
+fn assert_golden(name: &str, preview_buffer: DebugPreviewBuffer) {
+    let actual = render(preview_buffer);
+    let reference = PathBuf::from(format!("tests/golden/{}.png", name));
+
+    if env::var("ATLAS_UPDATE_GOLDEN").is_ok() {
+        write_png(&reference, DIMENSIONS, &actual).unwrap();
+        return;
+    }
+
+    assert!(
+        reference.exists(),
+        "Missing reference image {}, run the tests with ATLAS_UPDATE_GOLDEN=1 to create it",
+        reference.display()
+    );
+
+    let expected = read_png(&reference);
+    let (diff, mismatches) = diff_image(&expected, &actual);
+    let pixel_count = (DIMENSIONS[0] * DIMENSIONS[1]) as usize;
+
+    if mismatches as f32 > pixel_count as f32 * MAX_MISMATCH_RATIO {
+        let output_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("golden");
+        let actual_path = output_dir.join(format!("{}_actual.png", name));
+        let diff_path = output_dir.join(format!("{}_diff.png", name));
+        write_png(&actual_path, DIMENSIONS, &actual).unwrap();
+        write_png(&diff_path, DIMENSIONS, &diff).unwrap();
+
+        panic!(
+            "{} of {} pixels differ from {}, see {} and {}",
+            mismatches,
+            pixel_count,
+            reference.display(),
+            actual_path.display(),
+            diff_path.display()
+        );
+    }
+}
+
+#[test]
+fn golden_final_output() {
+    assert_golden("final_output", DebugPreviewBuffer::FinalOutput);
+}
+
+#[test]
+fn golden_albedo() {
+    assert_golden("albedo", DebugPreviewBuffer::Albedo);
+}
+
+#[test]
+fn golden_normal() {
+    assert_golden("normal", DebugPreviewBuffer::Normal);
+}
+
+#[test]
+fn golden_position() {
+    assert_golden("position", DebugPreviewBuffer::Position);
+}